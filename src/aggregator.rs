@@ -0,0 +1,49 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::convert::TryFrom;
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Aggregator {
+    Average,
+    Sum,
+    Min,
+    Max,
+    Last,
+}
+
+impl TryFrom<&str> for Aggregator {
+    type Error = &'static str;
+
+    fn try_from(aggregator: &str) -> Result<Self, Self::Error> {
+        match aggregator {
+            "average" | "avg" => Ok(Aggregator::Average),
+            "sum" | "total" => Ok(Aggregator::Sum),
+            "min" | "minimum" => Ok(Aggregator::Min),
+            "max" | "maximum" => Ok(Aggregator::Max),
+            "last" => Ok(Aggregator::Last),
+            _ => Err("invalid aggregator"),
+        }
+    }
+}
+
+impl Aggregator {
+    /// Aggregate values, ordered by timestamp. None if there is no value.
+    pub fn aggregate(self: &Self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let value = match self {
+            Aggregator::Average => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregator::Sum => values.iter().sum(),
+            Aggregator::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
+            Aggregator::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            Aggregator::Last => values[values.len() - 1],
+        };
+
+        Some(value)
+    }
+}
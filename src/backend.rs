@@ -0,0 +1,197 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::error;
+use std::fmt;
+
+use crate::glob;
+use crate::metric::Metric;
+use crate::stage::Stage;
+
+#[derive(Debug, Clone)]
+pub struct NoRecord;
+
+impl fmt::Display for NoRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no record suitable")
+    }
+}
+
+impl error::Error for NoRecord {}
+
+/// (timestamp, value) points.
+pub type Points = Vec<(i64, f64)>;
+
+/// Rows of each scanned token range, as (token, row).
+pub type ScanResult<T> = Vec<Result<Vec<(i64, T)>, Box<dyn error::Error>>>;
+
+/// Storage operations used by commands.
+///
+/// `Session` implements it against cassandra, `MemoryBackend` keeps
+/// everything in memory.
+pub trait Backend {
+    fn is_dry_run(&self) -> bool;
+
+    fn fetch_metric(&self, metric_name: &str) -> Result<Metric, Box<dyn error::Error>>;
+    /// Missing metrics are not returned.
+    fn fetch_metrics(&self, metric_names: &[String]) -> Result<Vec<Metric>, Box<dyn error::Error>>;
+    fn create_metric(&self, metric_name: &str) -> Result<(), Box<dyn error::Error>>;
+    /// The metric's own `directories` entry is kept, as it may also be the
+    /// directory of other metrics: cleaning directories removes it once
+    /// nothing is left under it.
+    fn delete_metric(&self, metric_name: &str) -> Result<(), Box<dyn error::Error>>;
+    /// Delete metrics at once, returning each delete's result.
+    fn delete_metrics(&self, metric_names: &[String]) -> Vec<Result<(), Box<dyn error::Error>>>;
+    fn delete_directory(&self, directory: &str) -> Result<(), Box<dyn error::Error>>;
+    /// Delete directories at once, returning each delete's result.
+    fn delete_directories(&self, directories: &[String]) -> Vec<Result<(), Box<dyn error::Error>>>;
+
+    /// Names of metrics matching glob components.
+    fn find_metrics(&self, components: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>>;
+    /// Names of directories matching glob components.
+    fn find_directories(&self, components: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>>;
+    fn directory_has_metrics(&self, directory: &str) -> Result<bool, Box<dyn error::Error>>;
+    /// Check directories at once, returning each one's result.
+    fn directories_have_metrics(&self, directories: &[String]) -> Vec<Result<bool, Box<dyn error::Error>>>;
+    /// Number of metrics under a directory, at any depth.
+    fn count_directory_metrics(&self, directory: &str) -> Result<u64, Box<dyn error::Error>>;
+
+    /// Names of directories directly under any of the given directories.
+    fn find_child_directories(&self, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>>;
+    /// Names of metrics directly under any of the given directories.
+    fn find_child_metrics(&self, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>>;
+
+    /// Metrics in each (start_token, end_token) token range, ordered by
+    /// token. Ranges are scanned at once, returning each range's result.
+    fn scan_metrics(&self, ranges: &[(i64, i64)], updated_before: Option<u64>, limit: usize) -> ScanResult<Metric>;
+    /// Directories in each (start_token, end_token) token range, ordered by token.
+    fn scan_directories(&self, ranges: &[(i64, i64)], limit: usize) -> ScanResult<String>;
+
+    /// Points of a stage in [time_start, time_end), as (timestamp, value).
+    fn fetch_points(&self, metric: &Metric, stage: &Stage, time_start: i64, time_end: i64)
+        -> Result<Vec<(i64, f64)>, Box<dyn error::Error>>;
+    /// Points of (metric, stage, time_start, time_end) reads at once,
+    /// returning each read's result.
+    fn fetch_points_many(&self, reads: &[(&Metric, &Stage, i64, i64)]) -> Vec<Result<Points, Box<dyn error::Error>>>;
+    fn insert_point(&self, metric: &Metric, stage: &Stage, timestamp: i64, value: f64) -> Result<(), Box<dyn error::Error>>;
+    /// Insert (metric, stage, timestamp, value) points at once, returning each insert's result.
+    fn insert_points(&self, points: &[(&Metric, &Stage, i64, f64)]) -> Vec<Result<(), Box<dyn error::Error>>>;
+}
+
+/// Directories & all directories under them.
+fn sub_trees(backend: &dyn Backend, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>> {
+    let mut out = directories.to_vec();
+    let mut level = directories.to_vec();
+
+    while !level.is_empty() {
+        level = backend.find_child_directories(&level)?;
+        out.extend(level.iter().cloned());
+    }
+
+    out.sort();
+    out.dedup();
+
+    Ok(out)
+}
+
+/// Resolve glob components from the root, expanding at each level only the
+/// children matching the pattern so far. `**` expands whole sub-trees.
+pub fn walk(backend: &dyn Backend, components: &[&str], metrics: bool) -> Result<Vec<String>, Box<dyn error::Error>> {
+    let mut directories = vec![String::new()];
+
+    for (id, component) in components.iter().enumerate() {
+        let last = id == components.len() - 1;
+        let alternatives = glob::expand_braces(component);
+
+        if *component == "**" {
+            // As `**` also matches no component, the directories themselves
+            // may be names: they are children of their parents.
+            let parents = directories
+                .iter()
+                .filter(|directory| !directory.is_empty())
+                .map(|directory| match directory.rfind('.') {
+                    Some(pos) => directory[..pos].to_string(),
+                    None => String::new(),
+                })
+                .collect::<Vec<String>>();
+
+            directories = sub_trees(backend, &directories)?;
+
+            if !last {
+                continue;
+            }
+
+            directories.extend(parents);
+        } else if !last && alternatives.iter().all(|x| glob::is_literal(x)) {
+            // Literal directories do not need to be looked up: a missing one
+            // just has no children.
+            directories = directories
+                .iter()
+                .flat_map(|directory| {
+                    alternatives.iter().map(move |x| {
+                        if directory.is_empty() { x.to_string() } else { format!("{}.{}", directory, x) }
+                    })
+                })
+                .collect();
+            continue;
+        }
+
+        let children = if last && metrics {
+            backend.find_child_metrics(&directories)?
+        } else {
+            backend.find_child_directories(&directories)?
+        };
+
+        directories = children
+            .into_iter()
+            .filter(|name| glob::glob_match(&components[..=id], name))
+            .collect();
+
+        directories.sort();
+        directories.dedup();
+
+        if directories.is_empty() {
+            break;
+        }
+    }
+
+    Ok(directories)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    #[test]
+    fn walk_matches_filter() {
+        let backend = MemoryBackend::new();
+
+        for name in &["app.cpu", "app.cpu.user", "app.cpu.system", "app.mem.used", "app.disk0.io.read",
+                      "app.disk1.io.write", "ci.build.duration", "ci.app.cpu", "db.a.b.c.user"] {
+            backend.add_metric(name, 0);
+        }
+
+        let patterns = [
+            "*", "app.*", "*.cpu", "*.*.user", "app.cpu.*",
+            "**", "app.**", "**.user", "app.**.user", "**.cpu.**", "**.**.cpu", "db.**.c.*",
+            "{app,ci}.*", "app.{cpu,mem}.*", "{app,missing}.cpu",
+            "app.disk[0-9].io.*", "app.disk[!0].**", "?i.*.*",
+            "{app,db}.**.[a-u]*", "*.disk?.io.{read,write}", "app.c*", "missing.*",
+        ];
+
+        for pattern in patterns.iter() {
+            let components = pattern.split(".").collect::<Vec<&str>>();
+
+            let mut metrics = backend.find_metrics(&components).unwrap();
+            metrics.sort();
+            assert_eq!(walk(&backend, &components, true).unwrap(), metrics, "metrics of {}", pattern);
+
+            let mut directories = backend.find_directories(&components).unwrap();
+            directories.sort();
+            assert_eq!(walk(&backend, &components, false).unwrap(), directories, "directories of {}", pattern);
+        }
+    }
+}
@@ -3,167 +3,283 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::str::FromStr;
-use std::fmt;
 use std::error;
+use std::fmt;
+use std::fs;
 
-use crate::metric::Metric;
+use crate::backend::{NoRecord,walk};
+use crate::glob;
+use crate::metric::{default_config,Metric};
 use crate::session::Session;
 use crate::stage::Stage;
 use crate::timerange::TimeRange;
 
 use cassandra_cpp::Session as CassSession;
 use cassandra_cpp::Uuid as CassUuid;
-use cassandra_cpp::{Batch,BatchType,BindRustType,CassCollection,Cluster,Error,LogLevel,Map,RetryPolicy,Statement};
+use cassandra_cpp::{Batch,BatchType,BindRustType,CassCollection,Cluster,Error,LogLevel,Map,RetryPolicy,Ssl,SslVerifyFlag,Statement};
 use cassandra_cpp::{set_level,stmt};
 
 use chrono::Duration;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
-struct NoRecord;
+/// Retry policies of the driver
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Retry {
+    Default,
+    DowngradingConsistency,
+    Fallthrough,
+}
 
-impl fmt::Display for NoRecord {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "no record suitable")
+impl TryFrom<&str> for Retry {
+    type Error = &'static str;
+
+    fn try_from(retry: &str) -> Result<Self, Self::Error> {
+        match retry {
+            "default" => Ok(Retry::Default),
+            "downgrading" => Ok(Retry::DowngradingConsistency),
+            "fallthrough" => Ok(Retry::Fallthrough),
+            _ => Err("invalid retry policy"),
+        }
+    }
+}
+
+impl Retry {
+    fn policy(self: &Self) -> RetryPolicy {
+        match self {
+            Retry::Default => RetryPolicy::default_new(),
+            Retry::DowngradingConsistency => RetryPolicy::downgrading_consistency_new(),
+            Retry::Fallthrough => RetryPolicy::fallthrough_new(),
+        }
+    }
+}
+
+/// TLS settings; certificates & key are paths of PEM files
+#[derive(Clone,Debug,Default)]
+pub struct TlsOptions {
+    pub ca_cert: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub key_password: String,
+    /// Check the server certificate against the CA certificate
+    pub verify_cert: bool,
+    /// Check the server certificate matches the address it was reached at
+    pub verify_identity: bool,
+}
+
+/// Connection settings of a cluster
+#[derive(Clone,Debug)]
+pub struct ClusterOptions {
+    pub retry: Retry,
+    /// Request timeout, in seconds
+    pub request_timeout: i64,
+    pub protocol_version: i32,
+    pub credentials: Option<(String, String)>,
+    pub tls: Option<TlsOptions>,
+}
+
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        ClusterOptions {
+            retry: Retry::DowngradingConsistency,
+            request_timeout: 30,
+            protocol_version: 4,
+            credentials: None,
+            tls: None,
+        }
     }
 }
 
-impl error::Error for NoRecord {}
+fn ssl_context(tls: &TlsOptions) -> Result<Ssl, Box<dyn error::Error>> {
+    let mut ssl = Ssl::default();
+
+    if let Some(ca_cert) = &tls.ca_cert {
+        ssl.add_trusted_cert(&fs::read_to_string(ca_cert)?)?;
+    }
+
+    if let Some(cert) = &tls.cert {
+        ssl.set_cert(&fs::read_to_string(cert)?)?;
+    }
 
-pub fn connect(contact_points: &str) -> Result<CassSession, Error> {
+    if let Some(key) = &tls.key {
+        // The driver expects a nul-terminated password.
+        ssl.set_private_key(&fs::read_to_string(key)?, &format!("{}\0", tls.key_password))?;
+    }
+
+    let mut flags = vec![];
+    if tls.verify_cert {
+        flags.push(SslVerifyFlag::PEER_CERT);
+    }
+    if tls.verify_identity {
+        flags.push(SslVerifyFlag::PEER_IDENTITY);
+    }
+    if flags.is_empty() {
+        flags.push(SslVerifyFlag::NONE);
+    }
+    ssl.set_verify_flags(&flags);
+
+    Ok(ssl)
+}
+
+pub fn connect(contact_points: &str, options: &ClusterOptions) -> Result<CassSession, Box<dyn error::Error>> {
     set_level(LogLevel::DISABLED);
 
     let mut cluster = Cluster::default();
     cluster.set_contact_points(contact_points).unwrap();
     cluster.set_load_balance_round_robin();
-    cluster.set_retry_policy(RetryPolicy::downgrading_consistency_new());
-    cluster.set_request_timeout(Duration::seconds(30));
-    cluster.set_protocol_version(4)?;
+    cluster.set_retry_policy(options.retry.policy());
+    cluster.set_request_timeout(Duration::seconds(options.request_timeout));
+    cluster.set_protocol_version(options.protocol_version)?;
+
+    if let Some((username, password)) = &options.credentials {
+        cluster.set_credentials(username, password)?;
+    }
+
+    if let Some(tls) = &options.tls {
+        cluster.set_ssl(&mut ssl_context(tls)?);
+    }
 
-    cluster.connect()
+    Ok(cluster.connect()?)
 }
 
-pub fn prepare_component_query(table_name: &str, arguments: &Vec<&str>) -> Result<Statement, Error> {
-    let mut q = format!("SELECT parent, name FROM biggraphite_metadata.{} WHERE ", table_name);
-    let mut component_number = 0;
-    let mut components = vec![];
+#[derive(Debug, Clone)]
+pub struct ComponentLimit(String);
 
-    for (id, component) in arguments.iter().enumerate() {
-        let mut operator = "=";
+impl fmt::Display for ComponentLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-        if *component == "*" {
-            component_number += 1;
-            continue;
-        }
+impl error::Error for ComponentLimit {}
 
-        if component_number != 0 {
-            q.push_str("AND ");
-        }
+/// Maximum number of components in a name, from the number of `component_N`
+/// columns of the metadata tables (the last one only holds `__END__`).
+pub fn fetch_max_components(session: &Session) -> Result<usize, Box<dyn error::Error>> {
+    let mut query = stmt!("SELECT column_name FROM system_schema.columns WHERE keyspace_name = ? AND table_name = 'metrics';");
+    query.set_consistency(session.read_consistency())?;
+    query.bind(0, session.metadata_keyspace())?;
 
-        if component.ends_with("*") {
-            operator = "LIKE";
-        }
+    let result = session.metadata_session().execute(&query).wait()?;
+    let mut columns = 0;
 
-        q.push_str(format!("component_{} {} ? ", id, operator).as_str());
-        component_number += 1;
-        components.push(component.replace("*", "%"));
+    for row in result.iter() {
+        let column : String = row.get_column(0)?.get_string()?;
+        if column.starts_with("component_") {
+            columns += 1;
+        }
     }
 
-    if component_number != 0 {
-        q.push_str("AND ");
+    if columns == 0 {
+        return Err(ComponentLimit(String::from("could not find component columns in schema, use --max-components")).into());
     }
 
-    // Adding last component for __END__.
-    q.push_str(format!("component_{} = ? ALLOW FILTERING;", component_number).as_str());
-    components.push("__END__".to_string());
+    Ok(columns - 1)
+}
 
-    let mut query = stmt!(q.as_str());
+/// Server side predicate for a glob component: equality for literals, LIKE
+/// on the literal prefix otherwise, none if there is no prefix. Results must
+/// still be filtered with `glob_match`.
+fn component_predicate(component: &str) -> Option<(&'static str, String)> {
+    if glob::is_literal(component) {
+        return Some(("=", component.to_string()));
+    }
 
-    for (id, arg) in components.iter().enumerate() {
-        query.bind(id, arg.as_str())?;
+    let prefix = glob::literal_prefix(component);
+    if prefix.is_empty() {
+        return None;
     }
 
-    Ok(query)
+    Some(("LIKE", format!("{}%", prefix)))
 }
 
-pub fn prepare_component_query_globstar(table_name: &str, arguments: &Vec<&str>) -> Result<Vec<Statement>, Error> {
-    let _q = format!("SELECT parent, name FROM biggraphite_metadata.{} WHERE ", table_name);
-    let _component_number = 0;
+fn build_component_query(keyspace: &str, table_name: &str, predicates: &[(usize, &str, String)]) -> Result<Statement, Error> {
+    let conditions = predicates
+        .iter()
+        .map(|(id, operator, _)| format!("component_{} {} ?", id, operator))
+        .collect::<Vec<String>>();
 
-    let mut out = vec![];
+    let q = format!(
+        "SELECT parent, name FROM {}.{} WHERE {} ALLOW FILTERING;",
+        keyspace, table_name, conditions.join(" AND ")
+    );
 
-    let pos_globstar = arguments.iter().enumerate().filter(|(_, &x)| x == "**").map(|(id, _)| id).collect::<Vec<usize>>();
-    if pos_globstar.len() != 1 {
-        // XXX return error
-        return Ok(vec![prepare_component_query(table_name, arguments)?]);
-    }
+    let mut query = stmt!(q.as_str());
 
-    let pos_globstar = pos_globstar[0];
-    let mut queries = vec![];
+    for (id, (_, _, value)) in predicates.iter().enumerate() {
+        query.bind(id, value.as_str())?;
+    }
 
-    let mut init_args = vec![];
-    let mut end_args = arguments[pos_globstar+1..].to_vec();
-    end_args.push("__END__");
+    Ok(query)
+}
 
-    for (id, el) in arguments[0..pos_globstar].iter().enumerate() {
-        if *el == "*" {
-            continue;
-        }
+pub fn prepare_component_query(keyspace: &str, table_name: &str, arguments: &[&str]) -> Result<Statement, Error> {
+    let mut predicates = vec![];
 
-        if el.ends_with("*") {
-            init_args.push((id, "LIKE", el.replace("*", "%")));
-        } else {
-            init_args.push((id, "=", el.to_string()));
+    for (id, component) in arguments.iter().enumerate() {
+        if let Some((operator, value)) = component_predicate(component) {
+            predicates.push((id, operator, value));
         }
     }
 
-    let components = 16;
+    // Adding last component for __END__.
+    predicates.push((arguments.len(), "=", "__END__".to_string()));
 
-    for id in init_args.len()..(components-end_args.len()+1) {
-        let mut current_query = init_args.to_vec();
+    build_component_query(keyspace, table_name, &predicates)
+}
 
-        for (sub_id, el) in end_args.iter().enumerate() {
-            if *el == "*" {
-                continue;
-            }
+/// Queries for a glob with `**`s: one per possible name length, up to
+/// `max_components`. Components before the first `**` are constrained from
+/// the start, components after the last one from the end; components in
+/// between only client side.
+pub fn prepare_component_query_globstar(keyspace: &str, table_name: &str, arguments: &[&str], max_components: usize) -> Result<Vec<Statement>, Box<dyn error::Error>> {
+    let pos_globstar = arguments.iter().enumerate().filter(|(_, &x)| x == "**").map(|(id, _)| id).collect::<Vec<usize>>();
+    let min_components = arguments.len() - pos_globstar.len();
 
-            if el.ends_with("*") {
-                current_query.push((sub_id + id, "LIKE", el.replace("*", "%")));
-            } else {
-                current_query.push((sub_id + id, "=", el.to_string()));
-            }
-        }
+    if min_components > max_components {
+        return Err(ComponentLimit(format!(
+            "pattern has {} components, metric names have at most {} (see --max-components)",
+            min_components, max_components
+        )).into());
+    }
 
-        queries.push(current_query);
+    if pos_globstar.is_empty() {
+        return Ok(vec![prepare_component_query(keyspace, table_name, arguments)?]);
     }
 
-    for query in &queries {
-        let mut current_query = _q.to_string();
+    let prefix = &arguments[0..pos_globstar[0]];
+    let suffix = &arguments[pos_globstar[pos_globstar.len() - 1]+1..];
 
-        for el in query {
-            if el.0 != 0 {
-                current_query.push_str("AND ");
-            }
+    let mut out = vec![];
 
-            current_query.push_str(&format!("component_{} {} ? ", el.0, el.1));
-        }
+    for length in min_components..=max_components {
+        let mut predicates = vec![];
 
-        current_query.push_str(&String::from("ALLOW FILTERING;"));
+        for (id, component) in prefix.iter().enumerate() {
+            if let Some((operator, value)) = component_predicate(component) {
+                predicates.push((id, operator, value));
+            }
+        }
 
-        let mut statement = stmt!(&current_query);
-        for (id, el) in query.iter().enumerate() {
-            statement.bind(id, el.2.as_str())?;
+        for (id, component) in suffix.iter().enumerate() {
+            if let Some((operator, value)) = component_predicate(component) {
+                predicates.push((length - suffix.len() + id, operator, value));
+            }
         }
 
-        out.push(statement);
+        predicates.push((length, "=", "__END__".to_string()));
+
+        out.push(build_component_query(keyspace, table_name, &predicates)?);
     }
 
     Ok(out)
 }
 
 pub fn fetch_metric(session: &Session, metric_name: &str) -> Result<Metric, Box<dyn error::Error>> {
-    let mut query = stmt!("SELECT * FROM biggraphite_metadata.metrics_metadata WHERE name = ?");
+    let query = format!("SELECT * FROM {}.metrics_metadata WHERE name = ?", session.metadata_keyspace());
+    let mut query = stmt!(query.as_str());
     query.set_consistency(session.read_consistency())?;
     query.bind(0, metric_name)?;
 
@@ -176,47 +292,402 @@ pub fn fetch_metric(session: &Session, metric_name: &str) -> Result<Metric, Box<
     Ok(result.first_row().unwrap().into())
 }
 
-pub fn fetch_points(session: &Session, m: &Metric, s: &Stage, time_start: i64, time_end: i64) -> Result<(), Error> {
-    let table_name = s.table_name();
+pub fn fetch_points(session: &Session, m: &Metric, s: &Stage, time_start: i64, time_end: i64) -> Result<Vec<(i64, f64)>, Error> {
+    fetch_points_many(session, &[(m, s, time_start, time_end)]).pop().unwrap()
+}
 
+fn prepare_points_query(session: &Session, m: &Metric, s: &Stage, range: &(i64, i64, i64)) -> Result<Statement, Error> {
     let q = format!(
-        "SELECT time_start_ms, offset, value FROM biggraphite.{} WHERE metric = ? AND time_start_ms = ? AND offset >= ? AND offset < ? ORDER BY offset",
-        table_name
+        "SELECT time_start_ms, offset, value FROM {}.{} WHERE metric = ? AND time_start_ms = ? AND offset >= ? AND offset < ? ORDER BY offset",
+        session.points_keyspace(), s.table_name()
     );
 
-    let ranges = TimeRange::new(&s, time_start, time_end).ranges();
-    // XXX concurrent
-    for range in ranges.iter() {
-        let mut query = stmt!(q.as_str());
-        query.bind(0, CassUuid::from_str(m.id().as_str())?)?;
-        query.bind(1, range.0)?;
-        query.bind(2, range.1 as i16)?;
-        query.bind(3, range.2 as i16)?;
+    let mut query = stmt!(q.as_str());
+    query.set_consistency(session.read_consistency())?;
+    query.bind(0, CassUuid::from_str(m.id().as_str())?)?;
+    query.bind(1, range.0)?;
+    query.bind(2, range.1 as i16)?;
+    query.bind(3, range.2 as i16)?;
 
-        let result =  session.points_session().execute(&query).wait()?;
+    Ok(query)
+}
 
-        for row in result.iter() {
-            let ts : i64 = row.get_column_by_name("time_start_ms".to_string())?.get_i64()?;
-            let offset : i16 = row.get_column_by_name("offset".to_string())?.get_i16()?;
-            let value : f64 = row.get_column_by_name("value".to_string())?.get_f64()?;
+/// Items of an iterator of started queries, keeping up to `max_in_flight`
+/// of them started ahead of the one returned, in order.
+pub struct InFlight<I: Iterator> {
+    items: I,
+    in_flight: VecDeque<I::Item>,
+    max_in_flight: usize,
+}
+
+impl<I: Iterator> InFlight<I> {
+    pub fn new(items: I, max_in_flight: usize) -> Self {
+        InFlight {
+            items: items,
+            in_flight: VecDeque::new(),
+            max_in_flight: std::cmp::max(max_in_flight, 1),
+        }
+    }
+}
 
-            let ts = ts / 1000;
-            let offset : i64 = offset as i64 * s.precision_as_seconds();
+impl<I: Iterator> Iterator for InFlight<I> {
+    type Item = I::Item;
 
-            println!("{:?};{:?}", ts + offset, value);
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.in_flight.len() < self.max_in_flight {
+            match self.items.next() {
+                Some(item) => self.in_flight.push_back(item),
+                None => break,
+            }
         }
+
+        self.in_flight.pop_front()
     }
+}
+
+/// Points of (metric, stage, time_start, time_end) reads, returning each
+/// read's result. Partition queries of all reads share up to
+/// read_parallelism slots.
+pub fn fetch_points_many(session: &Session, reads: &[(&Metric, &Stage, i64, i64)]) -> Vec<Result<Vec<(i64, f64)>, Error>> {
+    let partitions = reads
+        .iter()
+        .enumerate()
+        .flat_map(|(id, (_, s, time_start, time_end))| {
+            TimeRange::new(s, *time_start, *time_end).ranges().into_iter().map(move |range| (id, range))
+        })
+        .collect::<Vec<(usize, (i64, i64, i64))>>();
+
+    let mut out : Vec<Result<Vec<(i64, f64)>, Error>> = reads.iter().map(|_| Ok(vec![])).collect();
+
+    let queries = partitions.iter().map(|(id, range)| {
+        let (m, s, _, _) = reads[*id];
+        (*id, prepare_points_query(session, m, s, range).map(|query| session.points_session().execute(&query)))
+    });
+
+    // Results are consumed in partition order so points stay sorted by
+    // timestamp.
+    for (id, future) in InFlight::new(queries, session.read_parallelism()) {
+        let s = reads[id].1;
+        let result = future.and_then(|future| future.wait()).and_then(|result| {
+            let mut points = vec![];
+
+            for row in result.iter() {
+                let ts : i64 = row.get_column_by_name("time_start_ms".to_string())?.get_i64()?;
+                let offset : i16 = row.get_column_by_name("offset".to_string())?.get_i16()?;
+                let value : f64 = row.get_column_by_name("value".to_string())?.get_f64()?;
+
+                let ts = ts / 1000;
+                let offset : i64 = offset as i64 * s.precision_as_seconds();
+
+                points.push((ts + offset, value));
+            }
+
+            Ok(points)
+        });
+
+        match (&mut out[id], result) {
+            (Ok(points), Ok(mut rows)) => points.append(&mut rows),
+            (out, Err(err)) if out.is_ok() => *out = Err(err),
+            _ => {},
+        }
+    }
+
+    out
+}
+
+fn prepare_point_insert(session: &Session, m: &Metric, s: &Stage, timestamp: i64, value: f64) -> Result<Statement, Error> {
+    let (time_start_ms, offset) = s.time_offset_ms(timestamp);
+
+    let query = format!(
+        "INSERT INTO {}.{} (metric, time_start_ms, offset, value) VALUES (?, ?, ?, ?);",
+        session.points_keyspace(), s.table_name()
+    );
+
+    let mut query = stmt!(&query);
+    query.set_consistency(session.write_consistency())?;
+    query.bind(0, CassUuid::from_str(m.id().as_str())?)?;
+    query.bind(1, time_start_ms)?;
+    query.bind(2, offset as i16)?;
+    query.bind(3, value)?;
+
+    Ok(query)
+}
+
+pub fn insert_point(session: &Session, m: &Metric, s: &Stage, timestamp: i64, value: f64) -> Result<(), Error> {
+    let query = prepare_point_insert(session, m, s, timestamp, value)?;
+
+    session.points_session().execute(&query).wait()?;
 
     Ok(())
 }
 
+/// async insert multiple points, returning each insert's result
+pub fn insert_points(session: &Session, points: &[(&Metric, &Stage, i64, f64)]) -> Vec<Result<(), Error>> {
+    let mut results = vec![];
+
+    for (m, s, timestamp, value) in points.iter() {
+        let result = prepare_point_insert(session, m, s, *timestamp, *value)
+            .map(|query| session.points_session().execute(&query));
+        results.push(result);
+    }
+
+    results
+        .into_iter()
+        .map(|result| result.and_then(|future| future.wait()).map(|_| ()))
+        .collect()
+}
+
+/// How glob patterns are resolved against metadata tables
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum FindStrategy {
+    Auto,
+    /// `component_N` predicates with ALLOW FILTERING
+    Filter,
+    /// Level by level through the `parent` column
+    Walk,
+}
+
+impl TryFrom<&str> for FindStrategy {
+    type Error = &'static str;
+
+    fn try_from(strategy: &str) -> Result<Self, Self::Error> {
+        match strategy {
+            "auto" => Ok(FindStrategy::Auto),
+            "filter" => Ok(FindStrategy::Filter),
+            "walk" => Ok(FindStrategy::Walk),
+            _ => Err("invalid find strategy"),
+        }
+    }
+}
+
+/// Maximum number of wildcard levels expanded by a walk, in `auto`.
+const WALK_MAX_WILDCARD_LEVELS : usize = 2;
+
+/// Choose how to resolve a pattern.
+///
+/// Walking costs a query per matching directory at each level but never
+/// scans a whole table. `auto` walks when the pattern's first component is
+/// literal and at most a couple of levels have to be expanded through
+/// wildcards: otherwise, a level of a wide tree fans out in many queries and
+/// filtering is cheaper. A `**` requires walking whole sub-trees, so `auto`
+/// always filters it.
+pub fn plan_find(strategy: FindStrategy, arguments: &[&str]) -> FindStrategy {
+    match strategy {
+        FindStrategy::Auto => {
+            if arguments.contains(&"**") {
+                return FindStrategy::Filter;
+            }
+
+            let literal = |component: &&str| glob::expand_braces(component).iter().all(|x| glob::is_literal(x));
+            let wildcard_levels = arguments
+                .iter()
+                .take(arguments.len().saturating_sub(1))
+                .filter(|component| !literal(component))
+                .count();
+
+            if arguments.first().map(literal).unwrap_or(false) && wildcard_levels <= WALK_MAX_WILDCARD_LEVELS {
+                FindStrategy::Walk
+            } else {
+                FindStrategy::Filter
+            }
+        },
+        strategy => strategy,
+    }
+}
+
+/// Names of `directories` or `metrics` entries matching glob components
+pub fn fetch_component_names(session: &Session, table_name: &str, arguments: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>> {
+    match plan_find(session.find_strategy(), arguments) {
+        FindStrategy::Walk => walk(session, arguments, table_name == "metrics"),
+        _ => filter_component_names(session, table_name, arguments),
+    }
+}
+
+fn filter_component_names(session: &Session, table_name: &str, arguments: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>> {
+    let queries = prepare_component_query_globstar(session.metadata_keyspace(), table_name, arguments, session.max_components()?)?;
+    let mut results = vec![];
+    let mut out = vec![];
+
+    for mut q in queries {
+        q.set_consistency(session.read_consistency())?;
+        results.push(session.metadata_session().execute(&q));
+    }
+
+    for result in results {
+        let rows = result.wait()?;
+        for row in rows.iter() {
+            let name = row.get_column_by_name("name".to_string())?.to_string();
+
+            // Server side predicates are only an approximation of the glob.
+            if glob::glob_match(arguments, &name) {
+                out.push(name);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Names of entries whose parent is one of the given directories ("" being
+/// the root, stored with a "." parent), up to read_parallelism queries at once.
+pub fn fetch_children(session: &Session, table_name: &str, directories: &[String]) -> Result<Vec<String>, Error> {
+    let q = format!("SELECT name FROM {}.{} WHERE parent = ?;", session.metadata_keyspace(), table_name);
+    let queries = directories.iter().map(|directory| -> Result<_, Error> {
+        let mut query = stmt!(q.as_str());
+        query.set_consistency(session.read_consistency())?;
+        query.bind(0, format!("{}.", directory).as_str())?;
+
+        Ok(session.metadata_session().execute(&query))
+    });
+
+    let mut out = vec![];
+
+    for future in InFlight::new(queries, session.read_parallelism()) {
+        let result = future?.wait()?;
+
+        for row in result.iter() {
+            out.push(row.get_column_by_name("name".to_string())?.to_string());
+        }
+    }
+
+    Ok(out)
+}
+
+fn prepare_has_metrics(session: &Session, directory: &str) -> Result<Statement, Error> {
+    let query = format!("SELECT name FROM {}.metrics WHERE parent LIKE ? LIMIT 1;", session.metadata_keyspace());
+    let mut query = stmt!(query.as_str());
+    let mut directory = String::from(directory);
+    directory.push_str(".%");
+    query.set_consistency(session.read_consistency())?;
+    query.bind(0, directory.as_str())?;
+
+    Ok(query)
+}
+
+pub fn directory_has_metrics(session: &Session, directory: &str) -> Result<bool, Error> {
+    let query = prepare_has_metrics(session, directory)?;
+    let result = session.metadata_session().execute(&query).wait()?;
+
+    Ok(result.row_count() != 0)
+}
+
+/// async check multiple directories, returning each check's result
+pub fn directories_have_metrics(session: &Session, directories: &[String]) -> Vec<Result<bool, Error>> {
+    let results = directories
+        .iter()
+        .map(|directory| {
+            prepare_has_metrics(session, directory).map(|query| session.metadata_session().execute(&query))
+        })
+        .collect::<Vec<Result<_, Error>>>();
+
+    results
+        .into_iter()
+        .map(|result| Ok(result?.wait()?.row_count() != 0))
+        .collect()
+}
+
+/// Number of metrics under a directory, at any depth.
+pub fn count_directory_metrics(session: &Session, directory: &str) -> Result<u64, Error> {
+    let query = format!("SELECT COUNT(*) FROM {}.metrics WHERE parent LIKE ?;", session.metadata_keyspace());
+    let mut query = stmt!(query.as_str());
+    query.set_consistency(session.read_consistency())?;
+    query.bind(0, format!("{}.%", directory).as_str())?;
+
+    let result = session.metadata_session().execute(&query).wait()?;
+    let count = match result.first_row() {
+        Some(row) => row.get_column(0)?.get_i64()?,
+        None => 0,
+    };
+
+    Ok(count as u64)
+}
+
+/// Scan metrics metadata in token ranges (start_token, end_token), optionally
+/// only metrics not updated since `updated_before` (ms). Ranges are queried
+/// at once.
+pub fn scan_metrics(session: &Session, ranges: &[(i64, i64)], updated_before: Option<u64>, limit: usize) -> Vec<Result<Vec<(i64, Metric)>, Error>> {
+    let filter = match updated_before {
+        Some(cutoff) => format!("updated_on <= maxTimeuuid({}) AND ", cutoff),
+        None => String::new(),
+    };
+
+    let q = format!(
+        "SELECT id, name, token(name), config, created_on, updated_on \
+         FROM {}.metrics_metadata WHERE {}token(name) > ? AND token(name) < ? LIMIT {};",
+        session.metadata_keyspace(), filter, limit
+    );
+
+    let results = ranges
+        .iter()
+        .map(|(start_token, end_token)| {
+            let mut query = stmt!(q.as_str());
+            query.set_consistency(session.read_consistency())?;
+            query.bind(0, *start_token)?;
+            query.bind(1, *end_token)?;
+
+            Ok(session.metadata_session().execute(&query))
+        })
+        .collect::<Vec<Result<_, Error>>>();
+
+    results
+        .into_iter()
+        .map(|result| {
+            let mut out = vec![];
+
+            for row in result?.wait()?.iter() {
+                let token = row.get_column(2)?.get_i64()?;
+                out.push((token, row.into()));
+            }
+
+            Ok(out)
+        })
+        .collect()
+}
+
+/// Scan directories in token ranges (start_token, end_token), at once.
+pub fn scan_directories(session: &Session, ranges: &[(i64, i64)], limit: usize) -> Vec<Result<Vec<(i64, String)>, Error>> {
+    let q = format!(
+        "SELECT name, token(name) FROM {}.directories WHERE token(name) > ? AND token(name) < ? LIMIT {};",
+        session.metadata_keyspace(), limit
+    );
+
+    let results = ranges
+        .iter()
+        .map(|(start_token, end_token)| {
+            let mut query = stmt!(q.as_str());
+            query.set_consistency(session.read_consistency())?;
+            query.bind(0, *start_token)?;
+            query.bind(1, *end_token)?;
+
+            Ok(session.metadata_session().execute(&query))
+        })
+        .collect::<Vec<Result<_, Error>>>();
+
+    results
+        .into_iter()
+        .map(|result| {
+            let mut out = vec![];
+
+            for row in result?.wait()?.iter() {
+                let name = row.get_column_by_name("name".to_string())?.to_string();
+                let token = row.get_column(1)?.get_i64()?;
+                out.push((token, name));
+            }
+
+            Ok(out)
+        })
+        .collect()
+}
+
 /// async fetch multiple metrics
-pub fn fetch_metrics(session: &Session, metric_names: &Vec<String>) -> Result<Vec<Metric>, Error> {
+pub fn fetch_metrics(session: &Session, metric_names: &[String]) -> Result<Vec<Metric>, Error> {
     let mut results = vec![];
     let mut out = vec![];
 
+    let q = format!("SELECT * FROM {}.metrics_metadata WHERE name = ?", session.metadata_keyspace());
+
     for metric_name in metric_names.iter() {
-        let mut query = stmt!("SELECT * FROM biggraphite_metadata.metrics_metadata WHERE name = ?");
+        let mut query = stmt!(q.as_str());
         query.bind(0, metric_name.as_str())?;
         query.set_consistency(session.read_consistency())?;
 
@@ -239,6 +710,7 @@ pub fn fetch_metrics(session: &Session, metric_names: &Vec<String>) -> Result<Ve
 
 pub fn create_metric(session: &Session, metric: &str) -> Result<(), Error> {
     let mut batch = Batch::new(BatchType::LOGGED);
+    batch.set_consistency(session.write_consistency())?;
 
     let metrics_parts = metric.split(".").collect::<Vec<&str>>();
 
@@ -264,7 +736,8 @@ pub fn create_metric(session: &Session, metric: &str) -> Result<(), Error> {
             }
         }
 
-        let query = format!("INSERT INTO biggraphite_metadata.{}({}) VALUES ({});",
+        let query = format!("INSERT INTO {}.{}({}) VALUES ({});",
+            session.metadata_keyspace(),
             String::from("directories"),
             fields.join(", "),
             fields.iter().map(|_| String::from("?")).collect::<Vec<String>>().join(", ")
@@ -272,7 +745,8 @@ pub fn create_metric(session: &Session, metric: &str) -> Result<(), Error> {
 
         // before anything, create the "metrics" record.
         if d == 0 {
-            let query_metrics = format!("INSERT INTO biggraphite_metadata.{}({}) VALUES ({});",
+            let query_metrics = format!("INSERT INTO {}.{}({}) VALUES ({});",
+                session.metadata_keyspace(),
                 String::from("metrics"),
                 fields.join(", "),
                 fields.iter().map(|_| String::from("?")).collect::<Vec<String>>().join(", ")
@@ -297,20 +771,17 @@ pub fn create_metric(session: &Session, metric: &str) -> Result<(), Error> {
     }
 
     let query = format!(
-        "INSERT INTO biggraphite_metadata.metrics_metadata(name, config, id, created_on, updated_on) VALUES (?, ?, ?, now(), now())"
+        "INSERT INTO {}.metrics_metadata(name, config, id, created_on, updated_on) VALUES (?, ?, ?, now(), now())",
+        session.metadata_keyspace()
     );
 
     let uuid = Uuid::new_v4();
 
     let mut config = Map::new(0);
-    config.append_string("aggregator")?;
-    config.append_string("average")?;
-
-    config.append_string("carbon_xfilesfactor")?;
-    config.append_string("0.500000")?;
-
-    config.append_string("retention")?;
-    config.append_string("11520*60s:720*3600s:730*86400s")?;
+    for (key, value) in default_config() {
+        config.append_string(key)?;
+        config.append_string(value)?;
+    }
 
     let mut query = stmt!(&query);
     query.bind(0, metric)?; // name
@@ -324,38 +795,118 @@ pub fn create_metric(session: &Session, metric: &str) -> Result<(), Error> {
     // Write directories
     session.metadata_session().execute_batch(batch).wait()?;
 
-    println!("Metric was written.");
-
     Ok(())
 }
 
-pub fn delete_metric(session: &Session, name: &str) -> Result<(), Error> {
-    let namespace = "biggraphite_metadata";
-    let delete_metric_query = format!("DELETE FROM {}.metrics WHERE name = ?;", namespace);
-    let delete_metadata_query = format!("DELETE FROM {}.metrics_metadata WHERE name = ?;", namespace);
+fn prepare_delete(session: &Session, table_name: &str, name: &str) -> Result<Statement, Error> {
+    let query = format!("DELETE FROM {}.{} WHERE name = ?;", session.metadata_keyspace(), table_name);
+
+    let mut query = stmt!(query.as_str());
+    query.set_consistency(session.write_consistency())?;
+    query.bind(0, name)?;
 
-    let mut delete_metric_query = stmt!(delete_metric_query.as_str());
-    delete_metric_query.set_consistency(session.write_consistency())?;
-    delete_metric_query.bind(0, name)?;
-    session.metadata_session().execute(&delete_metric_query).wait()?;
+    Ok(query)
+}
 
-    let mut delete_metadata_query = stmt!(delete_metadata_query.as_str());
-    delete_metric_query.set_consistency(session.write_consistency())?;
-    delete_metadata_query.bind(0, name)?;
-    session.metadata_session().execute(&delete_metric_query).wait()?;
+/// Delete a metric's metadata; its `directories` entry is left to clean.
+pub fn delete_metric(session: &Session, name: &str) -> Result<(), Error> {
+    session.metadata_session().execute(&prepare_delete(session, "metrics", name)?).wait()?;
+    session.metadata_session().execute(&prepare_delete(session, "metrics_metadata", name)?).wait()?;
 
     Ok(())
 }
 
+/// async delete multiple metrics, returning each delete's result
+pub fn delete_metrics(session: &Session, names: &[String]) -> Vec<Result<(), Error>> {
+    let results = names
+        .iter()
+        .map(|name| {
+            ["metrics", "metrics_metadata"]
+                .iter()
+                .map(|table_name| {
+                    prepare_delete(session, table_name, name).map(|query| session.metadata_session().execute(&query))
+                })
+                .collect::<Result<Vec<_>, Error>>()
+        })
+        .collect::<Vec<Result<_, Error>>>();
+
+    results
+        .into_iter()
+        .map(|futures| {
+            for future in futures? {
+                future.wait()?;
+            }
 
-pub fn delete_directory(session: &Session, name: &str) -> Result<(), Error> {
-    let namespace = "biggraphite_metadata";
-    let delete_directory_query = format!("DELETE FROM {}.directories WHERE name = ?;", namespace);
+            Ok(())
+        })
+        .collect()
+}
 
-    let mut delete_directory_query = stmt!(delete_directory_query.as_str());
-    delete_directory_query.set_consistency(session.write_consistency())?;
-    delete_directory_query.bind(0, name)?;
-    session.metadata_session().execute(&delete_directory_query).wait()?;
+pub fn delete_directory(session: &Session, name: &str) -> Result<(), Error> {
+    session.metadata_session().execute(&prepare_delete(session, "directories", name)?).wait()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// async delete multiple directories, returning each delete's result
+pub fn delete_directories(session: &Session, names: &[String]) -> Vec<Result<(), Error>> {
+    let results = names
+        .iter()
+        .map(|name| {
+            prepare_delete(session, "directories", name).map(|query| session.metadata_session().execute(&query))
+        })
+        .collect::<Vec<Result<_, Error>>>();
+
+    results
+        .into_iter()
+        .map(|result| result?.wait().map(|_| ()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(strategy: FindStrategy, pattern: &str) -> FindStrategy {
+        plan_find(strategy, &pattern.split(".").collect::<Vec<&str>>())
+    }
+
+    #[test]
+    fn in_flight_keeps_order_within_window() {
+        use std::cell::Cell;
+
+        let started = Cell::new(0);
+        let queries = (0..1000).inspect(|_| started.set(started.get() + 1));
+
+        let mut finished = 0;
+        for id in InFlight::new(queries, 16) {
+            assert_eq!(id, finished);
+            finished += 1;
+
+            // The returned one included.
+            assert!(started.get() - finished < 16);
+        }
+
+        assert_eq!(finished, 1000);
+        assert_eq!(InFlight::new(0..3, 0).collect::<Vec<i32>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn plan_find_by_pattern_shape() {
+        assert_eq!(plan(FindStrategy::Auto, "app.cpu"), FindStrategy::Walk);
+        assert_eq!(plan(FindStrategy::Auto, "app.*.cpu.*"), FindStrategy::Walk);
+        assert_eq!(plan(FindStrategy::Auto, "{app,ci}.*.*.cpu"), FindStrategy::Walk);
+        // Wildcards from the root, or too many expanded levels.
+        assert_eq!(plan(FindStrategy::Auto, "*.cpu"), FindStrategy::Filter);
+        assert_eq!(plan(FindStrategy::Auto, "app.*.*.*.cpu"), FindStrategy::Filter);
+        assert_eq!(plan(FindStrategy::Auto, "app.**"), FindStrategy::Filter);
+    }
+
+    #[test]
+    fn plan_find_explicit_strategy() {
+        assert_eq!(plan(FindStrategy::Walk, "*.cpu"), FindStrategy::Walk);
+        assert_eq!(plan(FindStrategy::Filter, "app.cpu"), FindStrategy::Filter);
+        assert_eq!(plan(FindStrategy::Filter, "app.**"), FindStrategy::Filter);
+        assert_eq!(plan(FindStrategy::Walk, "app.**"), FindStrategy::Walk);
+    }
+}
@@ -3,10 +3,13 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
+pub mod carbon;
 pub mod clean;
 pub mod delete;
 pub mod info;
 pub mod list;
 pub mod local_clean;
+pub mod read;
+pub mod serve;
 pub mod stats;
 pub mod write;
@@ -0,0 +1,206 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::error;
+use std::io::{BufRead,BufReader};
+use std::net::{TcpListener,TcpStream,UdpSocket};
+use std::sync::mpsc::{Receiver,RecvTimeoutError,SyncSender,TrySendError};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration,Instant};
+
+use crate::Backend;
+use crate::cmd::write::{MetricCache,write_points};
+
+use chrono::Utc;
+
+const FLUSH_INTERVAL : Duration = Duration::from_secs(1);
+
+pub type Point = (String, f64, i64);
+
+/// Parse a plaintext protocol line: `path value timestamp`. Like carbon,
+/// NaN & infinite values are rejected, as are paths with empty components.
+pub fn parse_line(line: &str) -> Option<Point> {
+    let parts = line.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() != 3 || parts[0].split('.').any(|component| component.is_empty()) {
+        return None;
+    }
+
+    let value = parts[1].parse::<f64>().ok().filter(|value| value.is_finite())?;
+    let timestamp = parts[2].parse::<f64>().ok()? as i64;
+
+    // carbon uses -1 as "now"
+    let timestamp = if timestamp < 0 {
+        Utc::now().timestamp()
+    } else {
+        timestamp
+    };
+
+    Some((parts[0].to_string(), value, timestamp))
+}
+
+fn handle_tcp_connection(stream: TcpStream, sender: SyncSender<Point>) {
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("Could not read from connection: {}", err);
+                return;
+            }
+        };
+
+        match parse_line(&line) {
+            // Blocks when the queue is full, slowing down the sender.
+            Some(point) => if sender.send(point).is_err() {
+                return;
+            },
+            None => eprintln!("Invalid line: {}", line),
+        }
+    }
+}
+
+fn listen_tcp(listener: TcpListener, sender: SyncSender<Point>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let sender = sender.clone();
+                thread::spawn(move || handle_tcp_connection(stream, sender));
+            },
+            Err(err) => eprintln!("Connection failed: {}", err),
+        }
+    }
+}
+
+fn listen_udp(socket: UdpSocket, sender: SyncSender<Point>) {
+    let mut buf = [0; 65536];
+
+    loop {
+        let size = match socket.recv(&mut buf) {
+            Ok(size) => size,
+            Err(err) => {
+                eprintln!("Could not receive datagram: {}", err);
+                continue;
+            }
+        };
+
+        for line in String::from_utf8_lossy(&buf[0..size]).lines() {
+            match parse_line(line) {
+                // There is no way to slow down an UDP sender: drop points when full.
+                Some(point) => match sender.try_send(point) {
+                    Ok(_) => {},
+                    Err(TrySendError::Full(_)) => eprintln!("Queue is full, dropping point"),
+                    Err(TrySendError::Disconnected(_)) => return,
+                },
+                None => eprintln!("Invalid line: {}", line),
+            }
+        }
+    }
+}
+
+/// Wait for a first point, then gather more until batch_size or flush_interval.
+fn next_batch(receiver: &Receiver<Point>, batch_size: usize, flush_interval: Duration) -> Option<Vec<Point>> {
+    let first = receiver.recv().ok()?;
+    let deadline = Instant::now() + flush_interval;
+    let mut batch = vec![first];
+
+    while batch.len() < batch_size {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        match receiver.recv_timeout(timeout) {
+            Ok(point) => batch.push(point),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Some(batch)
+}
+
+/// Receive graphite plaintext protocol over TCP & UDP and write points,
+/// caching up to `cache_size` metrics.
+pub fn carbon_listen(backend: &dyn Backend, address: &str, queue_size: usize, batch_size: usize, cache_size: usize) -> Result<(), Box<dyn error::Error>> {
+    let tcp_listener = TcpListener::bind(address)?;
+    let udp_socket = UdpSocket::bind(address)?;
+
+    let (sender, receiver) = sync_channel::<Point>(queue_size);
+
+    let tcp_sender = sender.clone();
+    thread::spawn(move || listen_tcp(tcp_listener, tcp_sender));
+    thread::spawn(move || listen_udp(udp_socket, sender));
+
+    println!("Listening on {} (tcp & udp)", address);
+
+    // A single writer batches points & owns the metrics cache.
+    let mut cache = MetricCache::new(cache_size);
+
+    while let Some(batch) = next_batch(&receiver, batch_size, FLUSH_INTERVAL) {
+        let failed = write_points(backend, &mut cache, &batch);
+
+        if failed != 0 {
+            eprintln!("Failed to write {} point(s) out of {}", failed, batch.len());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse_line("app.cpu 1.5 1600000000"), Some((String::from("app.cpu"), 1.5, 1600000000)));
+        assert_eq!(parse_line("  app.cpu\t2 1600000000.7 "), Some((String::from("app.cpu"), 2., 1600000000)));
+        assert_eq!(parse_line("app.cpu 1.5"), None);
+        assert_eq!(parse_line("app.cpu one 1600000000"), None);
+
+        for value in &["nan", "NaN", "inf", "-inf", "infinity"] {
+            assert_eq!(parse_line(&format!("app.cpu {} 1600000000", value)), None);
+        }
+        for path in &["app..cpu", ".app.cpu", "app.cpu.", "."] {
+            assert_eq!(parse_line(&format!("{} 1 1600000000", path)), None);
+        }
+
+        let (_, _, timestamp) = parse_line("app.cpu 1 -1").unwrap();
+        assert!(timestamp > 1600000000);
+    }
+
+    #[test]
+    fn batches() {
+        let (sender, receiver) = sync_channel::<Point>(10);
+        let flush_interval = Duration::from_millis(50);
+
+        for id in 0..5 {
+            sender.send((format!("app.{}", id), 1., 1600000000)).unwrap();
+        }
+
+        // Full batches do not wait.
+        let start = Instant::now();
+        let batch = next_batch(&receiver, 3, flush_interval).unwrap();
+        assert_eq!(batch.iter().map(|x| x.0.as_str()).collect::<Vec<&str>>(), vec!["app.0", "app.1", "app.2"]);
+        assert!(start.elapsed() < flush_interval);
+
+        // Others are flushed after the interval.
+        let start = Instant::now();
+        assert_eq!(next_batch(&receiver, 3, flush_interval).unwrap().len(), 2);
+        assert!(start.elapsed() >= flush_interval);
+
+        // Points sent while waiting are part of the batch.
+        sender.send((String::from("app.5"), 1., 1600000000)).unwrap();
+        let delayed = sender.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            delayed.send((String::from("app.6"), 1., 1600000000)).unwrap();
+        });
+        assert_eq!(next_batch(&receiver, 3, flush_interval).unwrap().len(), 2);
+        handle.join().unwrap();
+
+        drop(sender);
+        assert!(next_batch(&receiver, 3, flush_interval).is_none());
+    }
+}
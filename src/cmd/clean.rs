@@ -3,146 +3,271 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
-use crate::Session;
+use std::collections::BTreeSet;
+use std::error;
 
-use cassandra_cpp::{BindRustType,Error};
-use cassandra_cpp::stmt;
+use crate::Backend;
+use crate::Metric;
+use crate::policy::RetentionPolicy;
+use crate::scan::{Counters,RateLimiter,ScanOptions,ScanState,scan,split_range};
 
 use chrono::Utc;
 
-pub fn metrics_clean(session: &Session, start_key: i64, end_key: i64, clean_metrics: bool, clean_directories: bool) -> Result<(), Error> {
-    let mut current_token = start_key;
-    let cutoff : u64 = (Utc::now().timestamp() as u64 - 86400 * 14) * 1000;
+fn scan_clean(backend: &dyn Backend, policy: &RetentionPolicy, start_key: i64, end_key: i64, clean_metrics: bool, clean_directories: bool,
+    options: &ScanOptions) -> Result<ScanState, Box<dyn error::Error>> {
+    let now = Utc::now().timestamp();
+
+    // Scan metrics outdated for the shortest max age, then check each one's.
+    let cutoff = policy.max_cutoff(now);
 
-    let namespace = "biggraphite_metadata";
     let batch_limit = 1000;
+    let limiter = RateLimiter::new(options.rate);
 
-    let query = format!("SELECT name, token(name) FROM {}.metrics_metadata \
-                         WHERE updated_on <= maxTimeuuid({}) and token(name) > ? and token(name) < ? LIMIT {};",
-                        namespace, cutoff, batch_limit);
-    let delete_metric_query = format!("DELETE FROM {}.metrics WHERE name = ?;", namespace);
-    let delete_metadata_query = format!("DELETE FROM {}.metrics_metadata WHERE name = ?;", namespace);
+    let mut state = match (&options.state_file, options.resume) {
+        (Some(path), true) => ScanState::load(path, "clean")?,
+        _ => ScanState::new("clean", "metrics", split_range(start_key, end_key, options.workers)),
+    };
 
-    let mut deleted_metrics_count = 0;
-    let mut scanned_metrics_count = 0;
-    let mut scanned_directories_count = 0;
-    let mut deleted_directories_count = 0;
+    // Metrics are not actually deleted in dry-run: remember them to tell
+    // which directories would end up empty. They are not saved in the state:
+    // a resumed dry-run only knows of those deleted since.
+    let mut deleted_metrics = BTreeSet::new();
 
     // clean metrics
-    loop {
-        if !clean_metrics || current_token >= end_key {
-            // println!("Stopping: {} >= {}", current_token, end_key);
-            break;
+    if state.phase == "metrics" {
+        if clean_metrics {
+            scan(&mut state, options, &limiter, batch_limit,
+                |ranges| backend.scan_metrics(ranges, Some(cutoff), batch_limit),
+                |metrics: &[&Metric], counters: &mut Counters| {
+                    *counters.entry(String::from("scanned_metrics")).or_insert(0) += metrics.len() as u64;
+
+                    let outdated = metrics
+                        .iter()
+                        .filter(|metric| metric.updated_on() <= policy.cutoff(metric.name(), now))
+                        .map(|metric| metric.name().clone())
+                        .collect::<Vec<String>>();
+
+                    for name in outdated.iter() {
+                        println!("Deleting metric {}", name);
+                    }
+
+                    if backend.is_dry_run() {
+                        *counters.entry(String::from("deleted_metrics")).or_insert(0) += outdated.len() as u64;
+                        deleted_metrics.extend(outdated);
+                        return Ok(());
+                    }
+
+                    // Deletes of a round are sent at once, two statements each.
+                    limiter.acquire(2 * outdated.len() as u64);
+                    for (name, result) in outdated.iter().zip(backend.delete_metrics(&outdated)) {
+                        match result {
+                            Ok(()) => *counters.entry(String::from("deleted_metrics")).or_insert(0) += 1,
+                            Err(err) => eprintln!("Failed to delete {}: {:?}", name, err),
+                        }
+                    }
+
+                    Ok(())
+                }
+            )?;
         }
 
-        let mut outdated_metrics_query = stmt!(query.as_str());
-        outdated_metrics_query.set_consistency(session.read_consistency())?;
-        outdated_metrics_query.bind(0, current_token)?;
-        outdated_metrics_query.bind(1, end_key)?;
+        // Directories are scanned over the same range, from its start.
+        let start = state.cursors.first().map(|c| c.start).unwrap_or(start_key);
+        let end = state.cursors.last().map(|c| c.end).unwrap_or(end_key);
 
-        let result = session.metadata_session().execute(&outdated_metrics_query).wait()?;
-        if result.row_count() == 0 {
-            break;
-        }
-        let mut queries = vec![];
-
-        for row in result.iter() {
-            let name = row.get_column_by_name("name".to_string())?.to_string();
-            scanned_metrics_count += 1;
-            let mut delete_metric_query = stmt!(delete_metric_query.as_str());
-            delete_metric_query.set_consistency(session.write_consistency())?;
-            delete_metric_query.bind(0, name.as_str())?;
-            queries.push(session.metadata_session().execute(&delete_metric_query));
-
-            let mut delete_metadata_query = stmt!(delete_metadata_query.as_str());
-            delete_metric_query.set_consistency(session.write_consistency())?;
-            delete_metadata_query.bind(0, name.as_str())?;
-            queries.push(session.metadata_session().execute(&delete_metadata_query));
-
-            deleted_metrics_count += 1;
-            current_token = row.get_column(1)?.get_i64()?;
-        }
+        state.phase = String::from("directories");
+        state.cursors = split_range(start, end, state.cursors.len());
 
-        if result.row_count() != batch_limit {
-            // println!("Stopping because count == 0");
-            break;
+        // Resuming from here on only scans directories.
+        if let Some(path) = &options.state_file {
+            state.save(path)?;
         }
+    }
 
-        for query in queries {
-            if let Err(err) = query.wait() {
-                eprintln!("Failed: {:?}", err);
+    // clean directories
+    if state.phase == "directories" && clean_directories {
+        scan(&mut state, options, &limiter, batch_limit,
+            |ranges| backend.scan_directories(ranges, batch_limit),
+            |names: &[&String], counters: &mut Counters| {
+                *counters.entry(String::from("scanned_directories")).or_insert(0) += names.len() as u64;
+
+                let names = names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+                let mut empty = vec![];
+
+                // Checks of a round are sent at once.
+                limiter.acquire(names.len() as u64);
+                for (name, has_metrics) in names.iter().zip(backend.directories_have_metrics(&names)) {
+                    if has_metrics? {
+                        if !backend.is_dry_run() {
+                            continue;
+                        }
+
+                        // It would be empty if all its metrics were deleted.
+                        let prefix = format!("{}.", name);
+                        let deleted = deleted_metrics
+                            .range::<String, _>(&prefix..)
+                            .take_while(|metric| metric.starts_with(&prefix))
+                            .count() as u64;
+
+                        if deleted == 0 {
+                            continue;
+                        }
+
+                        limiter.acquire(1);
+                        if backend.count_directory_metrics(name)? != deleted {
+                            continue;
+                        }
+                    }
+
+                    empty.push(name.clone());
+                }
+
+                for name in empty.iter() {
+                    println!("Deleting directory {}", name);
+                }
+
+                if backend.is_dry_run() {
+                    *counters.entry(String::from("deleted_directories")).or_insert(0) += empty.len() as u64;
+                    return Ok(());
+                }
+
+                // Like metrics, failed deletes are reported rather than
+                // failing the round: counters only include applied deletes.
+                limiter.acquire(empty.len() as u64);
+                for (name, result) in empty.iter().zip(backend.delete_directories(&empty)) {
+                    match result {
+                        Ok(()) => *counters.entry(String::from("deleted_directories")).or_insert(0) += 1,
+                        Err(err) => eprintln!("Failed to delete {}: {:?}", name, err),
+                    }
+                }
+
+                Ok(())
             }
-        }
+        )?;
     }
 
-    let list_directories_query = format!("SELECT name, token(name) FROM {}.directories WHERE token(name) > ? AND token(name) < ? LIMIT {};",
-        namespace, batch_limit);
-    let metric_query = format!("SELECT name FROM {}.metrics WHERE parent LIKE ? LIMIT 1", namespace);
-    let delete_directory_query = format!("DELETE FROM {}.directories WHERE name = ?;", namespace);
+    state.phase = String::from("done");
+    if let Some(path) = &options.state_file {
+        state.save(path)?;
+    }
 
-    current_token = start_key;
+    Ok(state)
+}
 
-    // clean directories
-    loop {
-        if !clean_directories || current_token >= end_key {
-            break;
-        }
+/// Clean outdated metrics & empty directories of (start_key, end_key).
+pub fn metrics_clean(backend: &dyn Backend, policy: &RetentionPolicy, start_key: i64, end_key: i64, clean_metrics: bool, clean_directories: bool,
+    options: &ScanOptions) -> Result<(), Box<dyn error::Error>> {
+    let state = scan_clean(backend, policy, start_key, end_key, clean_metrics, clean_directories, options)?;
 
-        let mut list_directories_query = stmt!(list_directories_query.as_str());
-        list_directories_query.set_consistency(session.read_consistency())?;
-        list_directories_query.bind(0, current_token)?;
-        list_directories_query.bind(1, end_key)?;
+    let deleted_metrics_count = state.counter("deleted_metrics");
+    let deleted_directories_count = state.counter("deleted_directories");
 
-        let list_result = session.metadata_session().execute(&list_directories_query).wait()?;
-        if list_result.row_count() == 0 {
-            break;
-        }
+    if backend.is_dry_run() {
+        println!("Would have deleted {} metrics, {} directories.", deleted_metrics_count, deleted_directories_count);
+    } else {
+        println!("Deleted {} metrics, {} directories.", deleted_metrics_count, deleted_directories_count);
+    }
+    println!("Scanned {} metrics, {} directories", state.counter("scanned_metrics"), state.counter("scanned_directories"));
 
-        let mut queries = vec![];
+    Ok(())
+}
 
-        for row in list_result.iter() {
-            let mut name = row.get_column_by_name("name".to_string())?.to_string();
-            let orig_name = name.clone();
-            name.push_str(".%");
-            current_token = row.get_column(1)?.get_i64()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
 
-            let mut metric_query = stmt!(metric_query.as_str());
-            metric_query.set_consistency(session.read_consistency())?;
-            metric_query.bind(0, name.as_str())?;
-            let query = session.metadata_session().execute(&metric_query);
+    const DAY_MS : u64 = 86400 * 1000;
 
-            queries.push((orig_name, query));
-        }
+    fn backend() -> MemoryBackend {
+        let now = Utc::now().timestamp_millis() as u64;
+        let backend = MemoryBackend::new();
 
-        let mut to_delete_queries = vec![];
+        backend.add_metric("app.old.cpu", now - 30 * DAY_MS);
+        backend.add_metric("app.old.mem", now - 30 * DAY_MS);
+        backend.add_metric("app.new.cpu", now);
+        backend.add_metric("ci.build.duration", now - 5 * DAY_MS);
 
-        for el in queries {
-            let result = el.1.wait()?;
-            scanned_directories_count += 1;
-            if result.row_count() != 0 {
-                continue;
-            }
+        backend
+    }
 
-            let mut delete_directory_query = stmt!(delete_directory_query.as_str());
-            delete_directory_query.set_consistency(session.write_consistency())?;
-            delete_directory_query.bind(0, el.0.as_str())?;
+    fn options() -> ScanOptions {
+        ScanOptions {
+            workers: 4,
+            rate: None,
+            state_file: None,
+            resume: false,
+        }
+    }
 
-            to_delete_queries.push(session.metadata_session().execute(&delete_directory_query));
+    #[test]
+    fn clean_outdated_metrics_and_empty_directories() {
+        let backend = backend();
 
-            deleted_directories_count += 1;
-        }
+        metrics_clean(&backend, &RetentionPolicy::new(14 * 86400), i64::MIN, i64::MAX, true, true, &options()).unwrap();
 
-        for to_delete in to_delete_queries {
-            to_delete.wait()?;
-        }
+        assert_eq!(backend.find_metrics(&["**"]).unwrap(), vec!["app.new.cpu", "ci.build.duration"]);
+        assert_eq!(backend.find_directories(&["*"]).unwrap(), vec!["app", "ci"]);
+        assert_eq!(backend.find_directories(&["app", "*"]).unwrap(), vec!["app.new"]);
+    }
 
-        if list_result.row_count() != batch_limit {
-            break;
-        }
+    #[test]
+    fn clean_with_policy() {
+        let backend = backend();
+        let policy = RetentionPolicy::parse("ci.** 3d", 60 * 86400).unwrap();
+
+        metrics_clean(&backend, &policy, i64::MIN, i64::MAX, true, false, &options()).unwrap();
+
+        assert_eq!(backend.find_metrics(&["**"]).unwrap(), vec!["app.new.cpu", "app.old.cpu", "app.old.mem"]);
     }
 
-    println!("Deleted {} metrics, {} directories.", deleted_metrics_count, deleted_directories_count);
-    println!("Scanned {} metrics, {} directories", scanned_metrics_count, scanned_directories_count);
+    #[test]
+    fn clean_dry_run() {
+        let policy = RetentionPolicy::new(14 * 86400);
+        let mut dry_run = backend();
+        dry_run.set_dry_run(true);
 
-    Ok(())
+        let state = scan_clean(&dry_run, &policy, i64::MIN, i64::MAX, true, true, &options()).unwrap();
+
+        assert_eq!(dry_run.find_metrics(&["**"]).unwrap().len(), 4);
+        assert_eq!(dry_run.find_directories(&["app", "*"]).unwrap(), vec!["app.new", "app.old"]);
+
+        // Same counts as an actual clean.
+        let deleted = scan_clean(&backend(), &policy, i64::MIN, i64::MAX, true, true, &options()).unwrap();
+        assert_eq!(state.counter("deleted_metrics"), 2);
+        assert_eq!(state.counter("deleted_metrics"), deleted.counter("deleted_metrics"));
+        assert_eq!(state.counter("deleted_directories"), deleted.counter("deleted_directories"));
+    }
+
+    #[test]
+    fn clean_resumes_directories_phase() {
+        let path = std::env::temp_dir()
+            .join(format!("bgutil-rs-{}-clean.state", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+
+        let backend = backend();
+        backend.delete_metrics(&[String::from("app.old.cpu"), String::from("app.old.mem")]);
+
+        // Directories were already scanned up to the end of the range.
+        let mut state = ScanState::new("clean", "directories", split_range(i64::MIN, i64::MAX, 1));
+        state.cursors[0].done = true;
+        state.counters.insert(String::from("deleted_metrics"), 2);
+        state.save(&path).unwrap();
+
+        let options = ScanOptions {
+            workers: 1,
+            rate: None,
+            state_file: Some(path.clone()),
+            resume: true,
+        };
+
+        let state = scan_clean(&backend, &RetentionPolicy::new(14 * 86400), i64::MIN, i64::MAX, true, true, &options).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(state.phase, "done");
+        assert_eq!(state.counter("deleted_metrics"), 2);
+        assert_eq!(state.counter("scanned_directories"), 0);
+        assert_eq!(backend.find_directories(&["app", "*"]).unwrap(), vec!["app.new", "app.old"]);
+    }
 }
@@ -5,38 +5,100 @@
  */
 use std::error;
 
-use cassandra_cpp::stmt;
-use cassandra_cpp::BindRustType;
-use crate::fetch_metric;
+use crate::Backend;
 
-use crate::Session;
-
-pub fn metric_delete(session: &Session, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
-    let mut query = stmt!("SELECT * FROM biggraphite_metadata.metrics_metadata WHERE name = ?");
-    query.bind(0, metric_name)?;
-
-    let result = session.metadata_session().execute(&query).wait()?;
-    if result.row_count() == 0 {
+pub fn metric_delete(backend: &dyn Backend, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
+    let metrics = backend.fetch_metrics(&[metric_name.to_string()])?;
+    if metrics.is_empty() {
         println!("Metric is not existing");
         return Ok(());
     }
 
-    let _metric = fetch_metric(session, metric_name)?;
+    backend.delete_metric(metric_name)?;
+    backend.delete_directory(metric_name)?;
+
+    Ok(())
+}
+
+pub fn metric_delete_recursive(backend: &dyn Backend, prefix: &str) -> Result<(), Box<dyn error::Error>> {
+    let mut glob = String::from(prefix);
+    glob.push_str(".**");
 
-    let mut query = stmt!("DELETE FROM biggraphite_metadata.metrics_metadata WHERE name = ?;");
-    query.bind(0, metric_name)?;
-    query.set_consistency(session.write_consistency())?;
-    session.metadata_session().execute(&query).wait()?;
+    let components = glob.split(".").collect::<Vec<&str>>();
 
-    let mut query = stmt!("DELETE FROM biggraphite_metadata.metrics_metadata WHERE name = ?;");
-    query.bind(0, metric_name)?;
-    query.set_consistency(session.write_consistency())?;
-    session.metadata_session().execute(&query).wait()?;
+    let mut deleted_metrics_count = 0;
+    let mut deleted_directories_count = 0;
 
-    let mut query = stmt!("DELETE FROM biggraphite_metadata.directories WHERE name = ?;");
-    query.bind(0, metric_name)?;
-    query.set_consistency(session.write_consistency())?;
-    session.metadata_session().execute(&query).wait()?;
+    for name in backend.find_metrics(&components)? {
+        println!("Deleting metric {}", name);
+        deleted_metrics_count += 1;
+        if backend.is_dry_run() {
+            continue;
+        }
+        backend.delete_metric(&name)?;
+    }
+
+    for name in backend.find_directories(&components)? {
+        println!("Deleting directory {}", name);
+        deleted_directories_count += 1;
+        if backend.is_dry_run() {
+            continue;
+        }
+        backend.delete_directory(&name)?;
+    }
+
+    if backend.is_dry_run() {
+        println!("Would have deleted {} metrics, {} directories.", deleted_metrics_count, deleted_directories_count);
+    } else {
+        println!("Deleted {} metrics, {} directories.", deleted_metrics_count, deleted_directories_count);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    fn backend() -> MemoryBackend {
+        let backend = MemoryBackend::new();
+
+        backend.add_metric("app.cpu", 0);
+        backend.add_metric("app.disk.sda", 0);
+        backend.add_metric("ci.duration", 0);
+
+        backend
+    }
+
+    #[test]
+    fn delete_metric() {
+        let backend = backend();
+
+        metric_delete(&backend, "app.cpu").unwrap();
+        metric_delete(&backend, "app.missing").unwrap();
+
+        assert_eq!(backend.find_metrics(&["**"]).unwrap(), vec!["app.disk.sda", "ci.duration"]);
+        assert!(backend.find_directories(&["app", "cpu"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn delete_recursive() {
+        let backend = backend();
+
+        metric_delete_recursive(&backend, "app").unwrap();
+
+        assert_eq!(backend.find_metrics(&["**"]).unwrap(), vec!["ci.duration"]);
+        assert!(backend.find_directories(&["app", "**"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn delete_recursive_dry_run() {
+        let mut backend = backend();
+        backend.set_dry_run(true);
+
+        metric_delete_recursive(&backend, "app").unwrap();
+
+        assert_eq!(backend.find_metrics(&["**"]).unwrap().len(), 3);
+    }
+}
@@ -5,13 +5,27 @@
  */
 use std::error;
 
-use crate::Session;
-use crate::fetch_metric;
+use crate::Backend;
 
-pub fn metric_info(session: &Session, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
-    let metric = fetch_metric(session, metric_name)?;
+pub fn metric_info(backend: &dyn Backend, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
+    let metric = backend.fetch_metric(metric_name)?;
 
     println!("{}", metric);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    #[test]
+    fn info_of_missing_metric() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+
+        assert!(metric_info(&backend, "app.cpu").is_ok());
+        assert!(metric_info(&backend, "app.mem").is_err());
+    }
+}
@@ -3,60 +3,323 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
+use std::collections::{BTreeMap,BTreeSet,HashSet};
+use std::convert::TryFrom;
+use std::error;
 
-use crate::prepare_component_query_globstar;
-use crate::fetch_metrics;
-use crate::Session;
-use cassandra_cpp::{Error};
+use crate::Backend;
+use crate::Metric;
+use crate::json;
 
-pub fn metric_list(session: &Session, glob: &str) -> Result<(), Error> {
+/// Output formats of list.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum ListFormat {
+    /// `d name` & `m name key=value...` lines
+    Text,
+    /// A single JSON array of entries
+    Json,
+    /// A JSON entry per line
+    Jsonl,
+    /// Names only, a line each
+    Names,
+}
+
+impl TryFrom<&str> for ListFormat {
+    type Error = &'static str;
+
+    fn try_from(format: &str) -> Result<Self, Self::Error> {
+        match format {
+            "text" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            "jsonl" => Ok(ListFormat::Jsonl),
+            "names" => Ok(ListFormat::Names),
+            _ => Err("invalid format"),
+        }
+    }
+}
+
+fn directory_json(name: &str) -> String {
+    format!("{{\"type\": \"directory\", \"name\": {}}}", json::string(name))
+}
+
+fn metric_json(metric: &Metric) -> String {
+    let config = metric.config_entries()
+        .iter()
+        .map(|(key, value)| format!("{}: {}", json::string(key), json::string(value)))
+        .collect::<Vec<String>>();
+
+    format!(
+        "{{\"type\": \"metric\", \"name\": {}, \"id\": {}, \"config\": {{{}}}, \"created_on\": {}, \"updated_on\": {}}}",
+        json::string(metric.name()), json::string(metric.id()), config.join(", "),
+        metric.created_on(), metric.updated_on()
+    )
+}
+
+fn count_lines(format: ListFormat, directories: Option<usize>, metrics: Option<usize>) -> Vec<String> {
+    let mut out = vec![];
+
+    match format {
+        ListFormat::Text => {
+            if let Some(directories) = directories {
+                out.push(format!("d {}", directories));
+            }
+            if let Some(metrics) = metrics {
+                out.push(format!("m {}", metrics));
+            }
+        },
+        ListFormat::Names => {
+            out.push(format!("{}", directories.unwrap_or(0) + metrics.unwrap_or(0)));
+        },
+        ListFormat::Json | ListFormat::Jsonl => {
+            let mut counts = vec![];
+            if let Some(directories) = directories {
+                counts.push(format!("\"directories\": {}", directories));
+            }
+            if let Some(metrics) = metrics {
+                counts.push(format!("\"metrics\": {}", metrics));
+            }
+            out.push(format!("{{{}}}", counts.join(", ")));
+        },
+    }
+
+    out
+}
+
+fn list_lines(backend: &dyn Backend, glob: &str, format: ListFormat, with_directories: bool, with_metrics: bool, count: bool)
+    -> Result<Vec<String>, Box<dyn error::Error>> {
     let components = glob.split(".").collect::<Vec<&str>>();
 
-    let query_directories = prepare_component_query_globstar("directories", &components)?;
-    let mut results = vec![];
+    let mut directories = BTreeSet::new();
+    if with_directories {
+        directories.extend(backend.find_directories(&components)?);
+    }
 
-    for mut q in query_directories {
-        q.set_consistency(session.read_consistency())?;
-        results.push(session.metadata_session().execute(&q));
+    let mut names = BTreeSet::new();
+    if with_metrics {
+        names.extend(backend.find_metrics(&components)?);
     }
 
-    for result in results {
-        let rows = result.wait()?;
-        for row in rows.iter() {
-            let name = row.get_column_by_name("name".to_string()).unwrap().to_string();
-            println!("d {}", name);
+    // A metric is also stored as a directory: only keep it as one if
+    // metrics are stored under it.
+    if with_directories && with_metrics {
+        let both = directories.intersection(&names).cloned().collect::<Vec<String>>();
 
+        for (name, has_metrics) in both.iter().zip(backend.directories_have_metrics(&both)) {
+            if !has_metrics? {
+                directories.remove(name);
+            }
         }
     }
 
-    let query_metrics = prepare_component_query_globstar("metrics", &components)?;
-    let mut results = vec![];
+    if count {
+        return Ok(count_lines(
+            format,
+            if with_directories { Some(directories.len()) } else { None },
+            if with_metrics { Some(names.len()) } else { None },
+        ));
+    }
+
+    if format == ListFormat::Names {
+        // Directories with metrics under them are also printed once.
+        return Ok(directories.union(&names).cloned().collect());
+    }
+
+    let mut metrics = backend.fetch_metrics(&names.into_iter().collect::<Vec<String>>())?;
+    metrics.sort_by(|a, b| a.name().cmp(b.name()));
+
+    let out = match format {
+        ListFormat::Text => {
+            directories
+                .iter()
+                .map(|name| format!("d {}", name))
+                .chain(metrics.iter().map(|metric| format!("m {}", metric)))
+                .collect()
+        },
+        ListFormat::Json | ListFormat::Jsonl => {
+            let entries = directories
+                .iter()
+                .map(|name| directory_json(name))
+                .chain(metrics.iter().map(metric_json))
+                .collect::<Vec<String>>();
+
+            if format == ListFormat::Json {
+                vec![format!("[{}]", entries.join(", "))]
+            } else {
+                entries
+            }
+        },
+        ListFormat::Names => unreachable!(),
+    };
 
-    for mut q in query_metrics {
-        q.set_consistency(session.read_consistency())?;
-        results.push(session.metadata_session().execute(&q));
+    Ok(out)
+}
+
+/// List directories and/or metrics matching glob, sorted by name.
+pub fn metric_list(backend: &dyn Backend, glob: &str, format: ListFormat, with_directories: bool, with_metrics: bool, count: bool)
+    -> Result<(), Box<dyn error::Error>> {
+    for line in list_lines(backend, glob, format, with_directories, with_metrics, count)? {
+        println!("{}", line);
     }
 
-    for result in results {
-        let res = result.wait();
-        if let Err(err) = res {
-            eprintln!("Query failed: {}", err);
-            continue;
+    Ok(())
+}
+
+struct Tree {
+    children: BTreeMap<String, BTreeSet<String>>,
+    metrics: HashSet<String>,
+}
+
+impl Tree {
+    fn lines(&self, name: &str, depth: usize, max_depth: Option<usize>, with_metrics: bool, out: &mut Vec<String>) {
+        let children = self.children.get(name);
+        let count = children.map(|x| x.len()).unwrap_or(0);
+        let is_metric = self.metrics.contains(name);
+
+        let indent = "  ".repeat(depth);
+        let label = if depth == 0 { name } else { name.rsplit('.').next().unwrap_or(name) };
+
+        // A metric is also stored as a directory: only show it as one if it has children.
+        if count != 0 || !is_metric {
+            out.push(format!("d {}{} ({})", indent, label, count));
+        }
+        if is_metric && with_metrics {
+            out.push(format!("m {}{}", indent, label));
         }
 
-        let rows = res.unwrap();
-        let names = rows
-            .iter()
-            .map(|x| {
-                x.get_column_by_name("name".to_string()).unwrap().to_string()
-            })
-            .collect::<Vec<String>>();
+        if max_depth == Some(depth) {
+            return;
+        }
 
-        let metrics = fetch_metrics(session, &names)?;
-        for metric in metrics {
-            println!("m {}", metric);
+        for child in children.into_iter().flatten() {
+            self.lines(child, depth + 1, max_depth, with_metrics, out);
         }
     }
+}
+
+fn tree_lines(backend: &dyn Backend, glob: &str, max_depth: Option<usize>, with_metrics: bool)
+    -> Result<Vec<String>, Box<dyn error::Error>> {
+    let components = glob.split(".").collect::<Vec<&str>>();
+
+    let roots = backend.find_directories(&components)?.into_iter().collect::<BTreeSet<String>>();
+    let mut tree = Tree {
+        children: BTreeMap::new(),
+        metrics: backend.find_metrics(&components)?.into_iter().collect(),
+    };
+
+    let mut level = roots.iter().cloned().collect::<Vec<String>>();
+    let mut depth = 0;
+
+    while !level.is_empty() {
+        tree.metrics.extend(backend.find_child_metrics(&level)?);
+
+        let directories = backend.find_child_directories(&level)?;
+        for directory in directories.iter() {
+            let parent = directory.rsplit_once('.').map(|x| x.0).unwrap_or("");
+            tree.children.entry(parent.to_string()).or_default().insert(directory.clone());
+        }
+
+        // Children of the last printed level are only fetched to be counted.
+        if max_depth == Some(depth) {
+            break;
+        }
+
+        level = directories;
+        depth += 1;
+    }
+
+    let mut out = vec![];
+    for root in roots.iter() {
+        tree.lines(root, 0, max_depth, with_metrics, &mut out);
+    }
+
+    Ok(out)
+}
+
+/// Print directories matching glob and their sub-directories & metrics as
+/// an indented tree, with their number of children. Levels are fetched
+/// through the `parent` column, all directories of a level at once.
+pub fn metric_tree(backend: &dyn Backend, glob: &str, max_depth: Option<usize>, with_metrics: bool)
+    -> Result<(), Box<dyn error::Error>> {
+    for line in tree_lines(backend, glob, max_depth, with_metrics)? {
+        println!("{}", line);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    fn backend() -> MemoryBackend {
+        let backend = MemoryBackend::new();
+
+        backend.add_metric("app.old.mem", 0);
+        backend.add_metric("app.old.cpu", 0);
+        backend.add_metric("app.new.cpu", 0);
+
+        backend
+    }
+
+    #[test]
+    fn list_names() {
+        let backend = backend();
+
+        let lines = list_lines(&backend, "app.*", ListFormat::Names, true, true, false).unwrap();
+        assert_eq!(lines, vec!["app.new", "app.old"]);
+
+        let lines = list_lines(&backend, "app.*.cpu", ListFormat::Names, true, true, false).unwrap();
+        assert_eq!(lines, vec!["app.new.cpu", "app.old.cpu"]);
+    }
+
+    #[test]
+    fn list_text_and_json() {
+        let backend = backend();
+
+        let lines = list_lines(&backend, "app.old.*", ListFormat::Text, false, true, false).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("m app.old.cpu id="));
+        assert!(lines[1].starts_with("m app.old.mem id="));
+
+        let lines = list_lines(&backend, "app.*", ListFormat::Jsonl, true, false, false).unwrap();
+        assert_eq!(lines, vec![
+            "{\"type\": \"directory\", \"name\": \"app.new\"}",
+            "{\"type\": \"directory\", \"name\": \"app.old\"}",
+        ]);
+    }
+
+    #[test]
+    fn list_count() {
+        let backend = backend();
+
+        // Metrics are not counted as directories too.
+        assert_eq!(list_lines(&backend, "app.*.*", ListFormat::Text, true, true, true).unwrap(), vec!["d 0", "m 3"]);
+        assert_eq!(list_lines(&backend, "app.*.*", ListFormat::Json, false, true, true).unwrap(), vec!["{\"metrics\": 3}"]);
+        assert_eq!(list_lines(&backend, "app.*", ListFormat::Text, true, true, true).unwrap(), vec!["d 2", "m 0"]);
+
+        // Unless metrics are stored under them.
+        backend.add_metric("app.old.cpu.user", 0);
+        assert_eq!(list_lines(&backend, "app.*.*", ListFormat::Text, true, true, true).unwrap(), vec!["d 1", "m 3"]);
+    }
+
+    #[test]
+    fn list_tree() {
+        let backend = backend();
+
+        assert_eq!(tree_lines(&backend, "app", None, true).unwrap(), vec![
+            "d app (2)",
+            "d   new (1)",
+            "m     cpu",
+            "d   old (2)",
+            "m     cpu",
+            "m     mem",
+        ]);
+
+        assert_eq!(tree_lines(&backend, "app", Some(1), false).unwrap(), vec![
+            "d app (2)",
+            "d   new (1)",
+            "d   old (2)",
+        ]);
+    }
+}
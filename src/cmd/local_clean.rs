@@ -5,131 +5,96 @@
  */
 use std::error;
 
-use crate::Session;
-
-use crate::delete_directory;
-use crate::delete_metric;
-use crate::fetch_metric;
-use crate::prepare_component_query_globstar;
-
-use cassandra_cpp::stmt;
-use cassandra_cpp::BindRustType;
+use crate::Backend;
+use crate::policy::RetentionPolicy;
 
 use chrono::Utc;
 
-fn clean_metrics_in_directory(session: &Session, directory: &str) -> Result<(), Box<dyn error::Error>> {
+fn clean_metrics_in_directory(backend: &dyn Backend, policy: &RetentionPolicy, directory: &str) -> Result<(), Box<dyn error::Error>> {
     // println!("Cleaning metrics in directory: '{}'", directory);
 
     let mut directory = String::from(directory);
     directory.push_str(".**");
 
     let components = directory.split(".").collect::<Vec<&str>>();
-    let mut results = vec![];
-
-    let query_metrics = prepare_component_query_globstar("metrics", &components)?;
-    let outdated_ts : u64 = (Utc::now().timestamp() as u64 - 14 * 86400) * 1000;
 
-    for mut q in query_metrics {
-        q.set_consistency(session.read_consistency())?;
-        results.push(session.metadata_session().execute(&q));
-    }
+    let now = Utc::now().timestamp();
 
-    for result in results {
-        let rows = result.wait()?;
-        for row in rows.iter() {
-            let name = row.get_column_by_name("name".to_string()).unwrap().to_string();
-            let metric = fetch_metric(session, &name);
+    for name in backend.find_metrics(&components)? {
+        let metric = backend.fetch_metric(&name);
 
-            if let Err(e) = metric {
-                eprintln!("Error while retrieving metric: {}", e);
-                continue;
-            }
+        if let Err(e) = metric {
+            eprintln!("Error while retrieving metric: {}", e);
+            continue;
+        }
 
-            let metric = metric.unwrap();
+        let metric = metric.unwrap();
 
-            if metric.updated_on() > outdated_ts {
-                continue;
-            }
+        if metric.updated_on() > policy.cutoff(metric.name(), now) {
+            continue;
+        }
 
-            println!("Deleting metric {}", metric.name());
-            if session.is_dry_run() {
-                continue;
-            }
-            delete_metric(session, metric.name())?;
+        println!("Deleting metric {}", metric.name());
+        if backend.is_dry_run() {
+            continue;
         }
+        backend.delete_metric(metric.name())?;
     }
 
     Ok(())
 }
 
-fn directory_has_metrics(session: &Session, directory: &str) -> Result<bool, Box<dyn error::Error>> {
-    let query = format!("SELECT name FROM biggraphite_metadata.metrics WHERE parent LIKE ? LIMIT 1;");
-    let mut query = stmt!(query.as_str());
-    let mut directory = String::from(directory);
-    directory.push_str(".%");
-    query.bind(0, directory.as_str())?;
-
-    let result = session.metadata_session().execute(&query).wait()?;
-
-    Ok(result.row_count() != 0)
-}
-
-fn clean_empty_directories_in_directory(session: &Session, directory: &str) -> Result<(), Box<dyn error::Error>> {
+fn clean_empty_directories_in_directory(backend: &dyn Backend, directory: &str) -> Result<(), Box<dyn error::Error>> {
     // println!("Cleaning empty directories in directory '{}'", directory);
 
     let mut directory = String::from(directory);
     directory.push_str(".**");
 
     let components = directory.split(".").collect::<Vec<&str>>();
-    let mut results = vec![];
 
-    let query_directories = prepare_component_query_globstar("directories", &components)?;
+    for name in backend.find_directories(&components)? {
+        if backend.directory_has_metrics(&name)? {
+            continue;
+        }
 
-    for mut q in query_directories {
-        q.set_consistency(session.read_consistency())?;
-        results.push(session.metadata_session().execute(&q));
+        println!("Deleting directory {}", name);
+        if backend.is_dry_run() {
+            continue;
+        }
+        backend.delete_directory(&name)?;
     }
 
-    for result in results {
-        let rows = result.wait()?;
-        for row in rows.iter() {
-            let name = row.get_column_by_name("name".to_string()).unwrap().to_string();
+    Ok(())
+}
 
-            if directory_has_metrics(session, &name)? {
-                continue;
-            }
+pub fn metrics_local_clean(backend: &dyn Backend, policy: &RetentionPolicy, directory: &str) -> Result<(), Box<dyn error::Error>> {
+    let components = directory.split(".").collect::<Vec<&str>>();
 
-            println!("Deleting directory {}", name);
-            if session.is_dry_run() {
-                continue;
-            }
-            delete_directory(session, &name)?;
-        }
+    for name in backend.find_directories(&components)? {
+        clean_metrics_in_directory(backend, policy, &name)?;
+        clean_empty_directories_in_directory(backend, &name)?;
     }
 
     Ok(())
 }
 
-pub fn metrics_local_clean(session: &Session, directory: &str) -> Result<(), Box<dyn error::Error>> {
-    let components = directory.split(".").collect::<Vec<&str>>();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
 
-    let query_directories = prepare_component_query_globstar("directories", &components)?;
-    let mut results = vec![];
+    #[test]
+    fn local_clean_directory() {
+        let now = Utc::now().timestamp_millis() as u64;
+        let backend = MemoryBackend::new();
 
-    for mut q in query_directories {
-        q.set_consistency(session.read_consistency())?;
-        results.push(session.metadata_session().execute(&q));
-    }
+        backend.add_metric("app.old.cpu", now - 30 * 86400 * 1000);
+        backend.add_metric("app.new.cpu", now);
+        backend.add_metric("ci.old.cpu", now - 30 * 86400 * 1000);
 
-    for result in results {
-        let rows = result.wait()?;
-        for row in rows.iter() {
-            let name = row.get_column_by_name("name".to_string()).unwrap().to_string();
+        metrics_local_clean(&backend, &RetentionPolicy::new(14 * 86400), "app").unwrap();
 
-            clean_metrics_in_directory(session, &name)?;
-            clean_empty_directories_in_directory(session, &name)?;
-        }
+        assert_eq!(backend.find_metrics(&["**"]).unwrap(), vec!["app.new.cpu", "ci.old.cpu"]);
+        assert_eq!(backend.find_directories(&["app", "*"]).unwrap(), vec!["app.new"]);
     }
-
-    Ok(())
 }
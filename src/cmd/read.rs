@@ -0,0 +1,105 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::convert::TryFrom;
+use std::error;
+
+use crate::Backend;
+use crate::Stage;
+use crate::format::Format;
+use crate::series::{Series,fetch_series,fetch_stage_series};
+
+use chrono::Utc;
+
+/// Points of a metric, None if the stage is not one of the metric's.
+fn read_series(backend: &dyn Backend, metric_name: &str, stage: &str, time_start: i64, time_end: i64) -> Result<Option<Series>, Box<dyn error::Error>> {
+    let metric = backend.fetch_metric(metric_name)?;
+
+    // Stitching is the default, a single stage can still be asked for.
+    let series = if stage == "auto" {
+        let stage = metric.stage_for(time_start, Utc::now().timestamp())?;
+        eprintln!("Using stage {}", stage);
+
+        fetch_stage_series(backend, &metric, &stage, time_start, time_end)?
+    } else if stage == "stitch" {
+        let series = fetch_series(backend, &metric, time_start, time_end, Utc::now().timestamp())?;
+
+        for segment in series.segments.iter() {
+            eprintln!("Using stage {} ({} -> {}, step {}s)", segment.stage, segment.time_start, segment.time_end, segment.step());
+        }
+
+        series
+    } else {
+        let available_stages = metric.stages()?;
+        let stage = Stage::try_from(stage)?;
+
+        if !available_stages.iter().any(|x| *x == stage) {
+            eprintln!("Could not find any stage matching {}", stage);
+            return Ok(None);
+        }
+
+        fetch_stage_series(backend, &metric, &stage, time_start, time_end)?
+    };
+
+    Ok(Some(series))
+}
+
+pub fn metric_read(backend: &dyn Backend, metric_name: &str, stage: &str, time_start: i64, time_end: i64, format: &Format, fill_nulls: bool) -> Result<(), Box<dyn error::Error>> {
+    if let Some(series) = read_series(backend, metric_name, stage, time_start, time_end)? {
+        print!("{}", format.format(&[series], fill_nulls));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    #[test]
+    fn read_stage() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stage = metric.stages().unwrap()[0];
+        backend.insert_point(&metric, &stage, 1599998400, 1.).unwrap();
+        backend.insert_point(&metric, &stage, 1599998460, 2.).unwrap();
+
+        let series = read_series(&backend, "app.cpu", "11520*60s", 1599998400, 1599998520).unwrap().unwrap();
+        assert_eq!(series.points(), vec![(1599998400, 1.), (1599998460, 2.)]);
+
+        assert!(read_series(&backend, "app.cpu", "10*1s", 1599998400, 1599998520).unwrap().is_none());
+        assert!(read_series(&backend, "app.mem", "11520*60s", 1599998400, 1599998520).is_err());
+    }
+
+    #[test]
+    fn read_auto_and_stitch() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stages = metric.stages().unwrap();
+        let now = Utc::now().timestamp();
+        let hour = now - now.rem_euclid(3600) - 86400;
+
+        backend.insert_point(&metric, &stages[0], hour, 1.).unwrap();
+        backend.insert_point(&metric, &stages[1], hour, 2.).unwrap();
+        backend.insert_point(&metric, &stages[1], hour - 10 * 86400, 3.).unwrap();
+
+        // 8 days of minutes cover yesterday, but not 10 days ago.
+        let series = read_series(&backend, "app.cpu", "auto", hour, hour + 60).unwrap().unwrap();
+        assert_eq!(series.points(), vec![(hour, 1.)]);
+
+        let series = read_series(&backend, "app.cpu", "auto", hour - 10 * 86400, hour + 3600).unwrap().unwrap();
+        assert_eq!(series.segments.len(), 1);
+        assert_eq!(series.points(), vec![(hour - 10 * 86400, 3.), (hour, 2.)]);
+
+        let series = read_series(&backend, "app.cpu", "stitch", hour - 10 * 86400, hour + 3600).unwrap().unwrap();
+        assert_eq!(series.segments.len(), 2);
+        assert_eq!(series.points(), vec![(hour - 10 * 86400, 3.), (hour, 1.)]);
+    }
+}
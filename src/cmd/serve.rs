@@ -0,0 +1,332 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io::{BufRead,BufReader,Read,Write};
+use std::net::{TcpListener,TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::Backend;
+use crate::format::Format;
+use crate::json;
+use crate::series::fetch_series;
+use crate::timeparse::parse_time;
+
+use chrono::Utc;
+
+const MAX_BODY_SIZE : usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+struct BadRequest(String);
+
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad request: {}", self.0)
+    }
+}
+
+impl error::Error for BadRequest {}
+
+struct Request {
+    path: String,
+    params: Vec<(String, String)>,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    fn params(&self, name: &str) -> Vec<&str> {
+        self.params.iter().filter(|(k, _)| k == name).map(|(_, v)| v.as_str()).collect()
+    }
+}
+
+fn decode_component(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i+1..i+3]).ok();
+                match hex.and_then(|x| u8::from_str_radix(x, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn parse_query_string(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let mut parts = x.splitn(2, '=');
+            let key = decode_component(parts.next().unwrap_or(""));
+            let value = decode_component(parts.next().unwrap_or(""));
+            (key, value)
+        })
+        .collect()
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, Box<dyn error::Error>> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let parts = line.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() < 2 {
+        return Err(BadRequest(String::from("invalid request line")).into());
+    }
+
+    let mut target = parts[1].splitn(2, '?');
+    let path = target.next().unwrap_or("").to_string();
+    let mut params = parse_query_string(target.next().unwrap_or(""));
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        let mut header = header.splitn(2, ':');
+        let name = header.next().unwrap_or("").trim().to_lowercase();
+        let value = header.next().unwrap_or("").trim();
+
+        if name == "content-length" {
+            content_length = value.parse::<usize>()?;
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(BadRequest(String::from("request body too large")).into());
+    }
+
+    // Grafana sends render requests as url-encoded form posts.
+    if content_length != 0 {
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        params.extend(parse_query_string(&String::from_utf8_lossy(&body)));
+    }
+
+    Ok(Request {
+        path: path,
+        params: params,
+    })
+}
+
+fn find(backend: &dyn Backend, request: &Request) -> Result<(&'static str, String), Box<dyn error::Error>> {
+    let query = match request.param("query") {
+        Some(query) => query,
+        None => return Err(BadRequest(String::from("missing query parameter")).into()),
+    };
+
+    let components = query.split(".").collect::<Vec<&str>>();
+
+    // A metric is also stored as a directory; leaves take precedence.
+    let mut nodes : BTreeMap<String, bool> = BTreeMap::new();
+    for name in backend.find_directories(&components)? {
+        nodes.insert(name, false);
+    }
+    for name in backend.find_metrics(&components)? {
+        nodes.insert(name, true);
+    }
+
+    let nodes = nodes
+        .iter()
+        .map(|(name, leaf)| {
+            let text = name.rsplit('.').next().unwrap_or(name);
+            let branch = if *leaf { 0 } else { 1 };
+
+            format!(
+                "{{\"text\": {}, \"id\": {}, \"leaf\": {}, \"expandable\": {}, \"allowChildren\": {}, \"context\": {{}}}}",
+                json::string(text), json::string(name), 1 - branch, branch, branch
+            )
+        })
+        .collect::<Vec<String>>();
+
+    Ok(("application/json", format!("[{}]", nodes.join(", "))))
+}
+
+fn render(backend: &dyn Backend, request: &Request) -> Result<(&'static str, String), Box<dyn error::Error>> {
+    let now = Utc::now().timestamp();
+    let time_start = parse_time(request.param("from").unwrap_or("-24h"), now)
+        .map_err(|err| BadRequest(err.to_string()))?;
+    let time_end = parse_time(request.param("until").unwrap_or("now"), now)
+        .map_err(|err| BadRequest(err.to_string()))?;
+
+    let mut series = vec![];
+
+    for target in request.params("target") {
+        let components = target.split(".").collect::<Vec<&str>>();
+        let names = backend.find_metrics(&components)?;
+
+        for metric in backend.fetch_metrics(&names)? {
+            series.push(fetch_series(backend, &metric, time_start, time_end, now)?);
+        }
+    }
+
+    let format = request.param("format").unwrap_or("json");
+    let format = match Format::try_from(format) {
+        Ok(format) => format,
+        Err(_) => return Err(BadRequest(format!("unsupported format '{}'", format)).into()),
+    };
+
+    // Like graphite-web, output every step of the requested range.
+    Ok((format.content_type(), format.format(&series, true)))
+}
+
+fn write_response(mut stream: &TcpStream, status: &str, content_type: &str, body: &str) -> Result<(), Box<dyn error::Error>> {
+    write!(stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+fn handle_connection(backend: &dyn Backend, stream: TcpStream) -> Result<(), Box<dyn error::Error>> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let result = match read_request(&stream) {
+        Err(err) => Err(err),
+        Ok(request) => {
+            match request.path.trim_end_matches('/') {
+                "/metrics/find" => find(backend, &request),
+                "/render" => render(backend, &request),
+                _ => {
+                    return write_response(&stream, "404 Not Found", "text/plain", "not found\n");
+                }
+            }
+        }
+    };
+
+    match result {
+        Ok((content_type, body)) => write_response(&stream, "200 OK", content_type, &body),
+        Err(err) => {
+            let status = if err.downcast_ref::<BadRequest>().is_some() {
+                "400 Bad Request"
+            } else {
+                eprintln!("Request failed: {}", err);
+                "500 Internal Server Error"
+            };
+
+            write_response(&stream, status, "text/plain", &format!("{}\n", err))
+        }
+    }
+}
+
+fn serve_connections(backend: &dyn Backend, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Connection failed: {}", err);
+                continue;
+            }
+        };
+
+        if let Err(err) = handle_connection(backend, stream) {
+            eprintln!("Connection failed: {}", err);
+        }
+    }
+}
+
+/// Serve graphite-web's /metrics/find & /render endpoints, `workers`
+/// requests at a time.
+pub fn metrics_serve(backend: Arc<dyn Backend + Send + Sync>, address: &str, workers: usize) -> Result<(), Box<dyn error::Error>> {
+    let listener = TcpListener::bind(address)?;
+    println!("Listening on {}", address);
+
+    // Workers accept connections on the same socket, sharing the session.
+    let mut handles = vec![];
+    for _ in 0..std::cmp::max(workers, 1) {
+        let backend = backend.clone();
+        let listener = listener.try_clone()?;
+
+        handles.push(thread::spawn(move || serve_connections(backend.as_ref(), listener)));
+    }
+
+    for handle in handles {
+        if handle.join().is_err() {
+            eprintln!("Worker failed");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    fn request(path: &str, query: &str) -> Request {
+        Request {
+            path: path.to_string(),
+            params: parse_query_string(query),
+        }
+    }
+
+    #[test]
+    fn find_nodes() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+        backend.add_metric("app.disk.sda", 0);
+
+        let (_, body) = find(&backend, &request("/metrics/find", "query=app.*")).unwrap();
+
+        assert_eq!(body, "[\
+            {\"text\": \"cpu\", \"id\": \"app.cpu\", \"leaf\": 1, \"expandable\": 0, \"allowChildren\": 0, \"context\": {}}, \
+            {\"text\": \"disk\", \"id\": \"app.disk\", \"leaf\": 0, \"expandable\": 1, \"allowChildren\": 1, \"context\": {}}]");
+
+        assert!(find(&backend, &request("/metrics/find", "")).is_err());
+    }
+
+    #[test]
+    fn render_targets() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+
+        let now = Utc::now().timestamp();
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stage = metric.stages().unwrap()[0];
+        backend.insert_point(&metric, &stage, now - 120, 4.).unwrap();
+
+        let (content_type, body) = render(&backend, &request("/render", "target=app.*&from=-5min&format=json")).unwrap();
+
+        assert_eq!(content_type, "application/json");
+        assert!(body.starts_with("[{\"target\": \"app.cpu\""));
+        assert!(body.contains(&format!("[4.0, {}]", now - 120 - (now - 120).rem_euclid(60))));
+
+        assert!(render(&backend, &request("/render", "target=app.*&format=pickle")).is_err());
+        assert!(render(&backend, &request("/render", "target=app.*&from=someday")).is_err());
+    }
+}
@@ -3,59 +3,68 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
-use cassandra_cpp::{BindRustType,Error};
-use cassandra_cpp::stmt;
-
-use std::collections::HashMap;
+use std::error;
 
+use crate::Backend;
 use crate::Metric;
-use crate::Session;
-
-pub fn metric_stats(session: &Session, start_key: i64, end_key: i64) -> Result<(), Error> {
-    let q =
-        "SELECT id, name, token(name), config, created_on, updated_on, read_on \
-         FROM biggraphite_metadata.metrics_metadata WHERE token(name) > ? LIMIT 1000";
-
-    let mut current_token = start_key;
-    let mut n = 0;
-    let mut points : u64 = 0;
-
-    let mut stats : HashMap<String, usize> = HashMap::new();
-
-    while current_token < end_key {
-        let mut query = stmt!(q);
-        query.bind(0, current_token)?;
-
-        let results = session.metadata_session().execute(&query).wait()?;
-
-        for row in results.iter() {
-            current_token = row.get_column(2)?.get_i64()?;
-
-            let metric : Metric = row.into();
-            let stages = match metric.stages() {
-                Ok(stages) => stages,
-                Err(_) => continue,
-            };
-
-            for stage in stages {
-                points += stage.points() as u64;
+use crate::scan::{Counters,RateLimiter,ScanOptions,ScanState,progress,scan,split_range};
+
+fn scan_stats(backend: &dyn Backend, start_key: i64, end_key: i64, options: &ScanOptions) -> Result<ScanState, Box<dyn error::Error>> {
+    let limiter = RateLimiter::new(options.rate);
+
+    let mut state = match (&options.state_file, options.resume) {
+        (Some(path), true) => ScanState::load(path, "stats")?,
+        _ => ScanState::new("stats", "metrics", split_range(start_key, end_key, options.workers)),
+    };
+
+    scan(&mut state, options, &limiter, 1000,
+        |ranges| backend.scan_metrics(ranges, None, 1000),
+        |metrics: &[&Metric], counters: &mut Counters| {
+            for metric in metrics {
+                let stages = match metric.stages() {
+                    Ok(stages) => stages,
+                    Err(_) => continue,
+                };
+
+                for stage in stages {
+                    *counters.entry(String::from("points")).or_insert(0) += stage.points() as u64;
+                }
+
+                // Per first component counts, as "roots.<component>".
+                let parts = metric.name().split(".").collect::<Vec<&str>>();
+                *counters.entry(format!("roots.{}", parts[0])).or_insert(0) += 1;
+
+                *counters.entry(String::from("metrics")).or_insert(0) += 1;
             }
 
-            let parts = metric.name().split(".").collect::<Vec<&str>>();
-            *stats.entry(String::from(parts[0])).or_insert(0) += 1;
-
-            n += 1;
+            Ok(())
         }
+    )?;
+
+    state.phase = String::from("done");
+    if let Some(path) = &options.state_file {
+        state.save(path)?;
     }
 
-    let p : f64 = ((current_token - start_key) / std::i64::MAX) as f64;
+    Ok(state)
+}
+
+/// Count metrics & points of (start_key, end_key).
+pub fn metric_stats(backend: &dyn Backend, start_key: i64, end_key: i64, options: &ScanOptions) -> Result<(), Box<dyn error::Error>> {
+    let state = scan_stats(backend, start_key, end_key, options)?;
 
-    println!("Range: {} -> {} ({:.4}%)", start_key, current_token, 100. * p);
-    println!("{} metrics", n);
-    println!("{} points", points);
+    let start = state.cursors.first().map(|c| c.start).unwrap_or(start_key);
+    let end = state.cursors.last().map(|c| c.end).unwrap_or(end_key);
+
+    println!("Range: {} -> {} ({:.4}%)", start, end, 100. * progress(&state.cursors));
+    println!("{} metrics", state.counter("metrics"));
+    println!("{} points", state.counter("points"));
     println!("-----");
 
-    let mut vec : Vec<(&String, &usize)> = stats.iter().collect();
+    let mut vec : Vec<(&str, &u64)> = state.counters
+        .iter()
+        .filter_map(|(name, count)| name.strip_prefix("roots.").map(|root| (root, count)))
+        .collect();
     vec.sort_by(|a, b| b.1.cmp(a.1));
 
     for (id, v) in vec.iter().enumerate() {
@@ -68,3 +77,32 @@ pub fn metric_stats(session: &Session, start_key: i64, end_key: i64) -> Result<(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    #[test]
+    fn stats_counts() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+        backend.add_metric("app.mem", 0);
+        backend.add_metric("ci.duration", 0);
+
+        let options = ScanOptions {
+            workers: 3,
+            rate: None,
+            state_file: None,
+            resume: false,
+        };
+
+        let state = scan_stats(&backend, i64::MIN, i64::MAX, &options).unwrap();
+
+        assert_eq!(state.counter("metrics"), 3);
+        // 11520*60s:720*3600s:730*86400s
+        assert_eq!(state.counter("points"), 3 * (11520 + 720 + 730));
+        assert_eq!(state.counter("roots.app"), 2);
+        assert_eq!(state.counter("roots.ci"), 1);
+    }
+}
@@ -3,47 +3,371 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
+use std::collections::{HashMap,HashSet};
 use std::error;
-use std::str::FromStr;
+use std::io::BufRead;
 
-use crate::Session;
+use crate::Backend;
+use crate::Metric;
 use crate::Stage;
+use crate::cmd::carbon::{Point,parse_line};
 
-use crate::create_metric;
-use crate::fetch_metric;
+use chrono::Utc;
 
-use cassandra_cpp::stmt;
-use cassandra_cpp::BindRustType;
-use cassandra_cpp::Uuid as CassUuid;
+/// Metrics by name. Beyond `capacity`, least recently used ones are evicted
+/// when trimmed.
+pub struct MetricCache {
+    capacity: usize,
+    // metric & last use
+    metrics: HashMap<String, (Metric, u64)>,
+    clock: u64,
+}
+
+impl MetricCache {
+    pub fn new(capacity: usize) -> Self {
+        MetricCache {
+            capacity: capacity,
+            metrics: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn get(self: &Self, name: &str) -> Option<&Metric> {
+        self.metrics.get(name).map(|(metric, _)| metric)
+    }
+
+    /// Mark a metric as used, false if it is not cached.
+    pub fn touch(self: &mut Self, name: &str) -> bool {
+        self.clock += 1;
+
+        match self.metrics.get_mut(name) {
+            Some((_, used)) => {
+                *used = self.clock;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn insert(self: &mut Self, metric: Metric) {
+        self.clock += 1;
+        self.metrics.insert(metric.name().to_string(), (metric, self.clock));
+    }
+
+    /// Evict least recently used metrics beyond capacity, and a tenth more
+    /// so that eviction does not happen on every batch.
+    pub fn trim(self: &mut Self) {
+        if self.metrics.len() <= self.capacity {
+            return;
+        }
+
+        let count = self.metrics.len() - self.capacity + self.capacity / 10;
+        let mut uses = self.metrics.values().map(|(_, used)| *used).collect::<Vec<u64>>();
+        let (_, threshold, _) = uses.select_nth_unstable(count - 1);
+        let threshold = *threshold;
+
+        self.metrics.retain(|_, (_, used)| *used > threshold);
+    }
+}
+
+pub fn metric_write(backend: &dyn Backend, metric_name: &str, value: f64, timestamp: i64) -> Result<(), Box<dyn error::Error>> {
+    let metrics = backend.fetch_metrics(&[metric_name.to_string()])?;
+    if metrics.is_empty() {
+        backend.create_metric(metric_name)?;
+        println!("Metric was written.");
+    }
+
+    let metric = backend.fetch_metric(metric_name)?;
+    let stages = metric.stages()?;
+    let level = metric.stage_index_for(timestamp, Utc::now().timestamp())?;
+
+    backend.insert_point(&metric, &stages[level], timestamp, value)?;
+    downsample(backend, &[(&metric, &stages, level, timestamp)])?;
+
+    Ok(())
+}
+
+/// Aggregate written points' buckets into each stage coarser than the one
+/// they were written into, stage by stage, following metrics' aggregator.
+fn downsample(backend: &dyn Backend, written: &[(&Metric, &Vec<Stage>, usize, i64)]) -> Result<(), Box<dyn error::Error>> {
+    let levels = written.iter().map(|(_, stages, _, _)| stages.len()).max().unwrap_or(0);
+
+    for level in 1..levels {
+        let mut buckets = HashSet::new();
+        let mut reads = vec![];
+        let mut targets = vec![];
+
+        for (metric, stages, written_level, timestamp) in written.iter() {
+            if level <= *written_level || level >= stages.len() {
+                continue;
+            }
+
+            let stage = &stages[level];
+            let precision = stage.precision_as_seconds();
+            let bucket = timestamp - timestamp.rem_euclid(precision);
+
+            if !buckets.insert((metric.id(), bucket)) {
+                continue;
+            }
+
+            reads.push((*metric, &stages[level - 1], bucket, bucket + precision));
+            targets.push((*metric, stage, bucket));
+        }
+
+        // Buckets of a level are read at once.
+        let mut points = vec![];
+        for ((metric, stage, bucket), (read, values)) in targets.into_iter().zip(reads.iter().zip(backend.fetch_points_many(&reads))) {
+            let values = values?
+                .iter()
+                .map(|(_, value)| *value)
+                .collect::<Vec<f64>>();
+
+            // Like carbon, skip buckets missing too many points.
+            let slots = stage.precision_as_seconds() / read.1.precision_as_seconds();
+            if (values.len() as f64) < metric.xfilesfactor()? * slots as f64 {
+                continue;
+            }
+
+            if let Some(value) = metric.aggregator()?.aggregate(&values) {
+                points.push((metric, stage, bucket, value));
+            }
+        }
+
+        for result in backend.insert_points(&points) {
+            result?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve points' metrics, using & filling the cache, creating unknown ones.
+fn resolve_metrics(backend: &dyn Backend, cache: &mut MetricCache, points: &[Point]) -> Result<(), Box<dyn error::Error>> {
+    let mut missing = points
+        .iter()
+        .map(|(name, _, _)| name.to_string())
+        .filter(|name| !cache.touch(name))
+        .collect::<Vec<String>>();
+
+    missing.sort();
+    missing.dedup();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    for metric in backend.fetch_metrics(&missing)? {
+        cache.insert(metric);
+    }
+
+    for name in missing.iter() {
+        if cache.get(name).is_some() {
+            continue;
+        }
+
+        backend.create_metric(name)?;
+        cache.insert(backend.fetch_metric(name)?);
+    }
+
+    Ok(())
+}
 
-use std::convert::TryFrom;
+/// Write points at once into metrics' stages, returning how many could not be written.
+pub fn write_points(backend: &dyn Backend, cache: &mut MetricCache, points: &[Point]) -> usize {
+    if let Err(err) = resolve_metrics(backend, cache, points) {
+        eprintln!("Could not resolve metrics: {}", err);
+    }
+
+    let now = Utc::now().timestamp();
+
+    let mut resolved = vec![];
+    for (name, value, timestamp) in points.iter() {
+        let metric = match cache.get(name) {
+            Some(metric) => metric,
+            None => continue,
+        };
+
+        match (metric.stages(), metric.stage_index_for(*timestamp, now)) {
+            (Ok(stages), Ok(level)) => resolved.push((metric, stages, level, *timestamp, *value)),
+            (Err(err), _) | (_, Err(err)) => eprintln!("Invalid retention for {}: {}", name, err),
+        }
+    }
+
+    // Like whisper, raw points go into the finest stage still covering them,
+    // coarser ones are downsampled.
+    let finest = resolved
+        .iter()
+        .map(|(metric, stages, level, timestamp, value)| (*metric, &stages[*level], *timestamp, *value))
+        .collect::<Vec<(&Metric, &Stage, i64, f64)>>();
 
-pub fn metric_write(session: &Session, metric_name: &str, value: f64, retention: &str, timestamp: i64) -> Result<(), Box<dyn error::Error>> {
-    let mut query = stmt!("SELECT * FROM biggraphite_metadata.metrics_metadata WHERE name = ?");
-    query.bind(0, metric_name)?;
+    let written = backend.insert_points(&finest)
+        .iter()
+        .zip(resolved.iter())
+        .filter(|(result, _)| result.is_ok())
+        .map(|(_, (metric, stages, level, timestamp, _))| (*metric, stages, *level, *timestamp))
+        .collect::<Vec<(&Metric, &Vec<Stage>, usize, i64)>>();
 
-    let result = session.metadata_session().execute(&query).wait()?;
-    if result.row_count() == 0 {
-        create_metric(session, metric_name)?;
+    if let Err(err) = downsample(backend, &written) {
+        eprintln!("Could not downsample points: {}", err);
     }
 
-    let stage = Stage::try_from(retention)?;
+    let failed = points.len() - written.len();
+
+    // Only now, as metrics of the batch were borrowed.
+    cache.trim();
+
+    failed
+}
+
+/// Write `metric value timestamp` lines, at most `parallelism` inserts at
+/// once, caching up to `cache_size` metrics.
+pub fn metric_write_lines(backend: &dyn Backend, reader: &mut dyn BufRead, parallelism: usize, cache_size: usize) -> Result<(), Box<dyn error::Error>> {
+    let mut cache = MetricCache::new(cache_size);
+    let mut points = vec![];
+
+    let mut accepted = 0;
+    let mut rejected = 0;
+    let mut failed = 0;
+
+    for line in reader.lines() {
+        let line = line?;
 
-    let metric = fetch_metric(session, metric_name)?;
-    let (time_start_ms, offset) = stage.time_offset_ms(timestamp);
+        if line.trim().is_empty() {
+            continue;
+        }
 
-    let query = format!(
-        "INSERT INTO biggraphite.{} (metric, time_start_ms, offset, value) VALUES (?, ?, ?, ?);",
-        stage.table_name()
-    );
+        match parse_line(&line) {
+            Some(point) => points.push(point),
+            None => {
+                eprintln!("Invalid line: {}", line);
+                rejected += 1;
+            }
+        }
 
-    let mut query = stmt!(&query);
-    query.bind(0, CassUuid::from_str(metric.id().as_str())?)?;
-    query.bind(1, time_start_ms)?;
-    query.bind(2, offset as i16)?;
-    query.bind(3, value)?;
+        if points.len() < parallelism {
+            continue;
+        }
 
-    session.points_session().execute(&query).wait()?;
+        let failed_points = write_points(backend, &mut cache, &points);
+        failed += failed_points;
+        accepted += points.len() - failed_points;
+        points.clear();
+    }
+
+    let failed_points = write_points(backend, &mut cache, &points);
+    failed += failed_points;
+    accepted += points.len() - failed_points;
+
+    println!("{} accepted, {} rejected, {} failed.", accepted, rejected, failed);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    /// Start of the hour a day ago, so that points go into the finest stage.
+    fn hour() -> i64 {
+        let now = Utc::now().timestamp();
+
+        now - now.rem_euclid(3600) - 86400
+    }
+
+    #[test]
+    fn write_creates_metric_and_downsamples() {
+        let backend = MemoryBackend::new();
+        let hour = hour();
+
+        for minute in 0..60 {
+            metric_write(&backend, "app.cpu", minute as f64, hour + minute * 60).unwrap();
+        }
+
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stages = metric.stages().unwrap();
+
+        let points = backend.fetch_points(&metric, &stages[0], hour, hour + 3600).unwrap();
+        assert_eq!(points.len(), 60);
+        assert_eq!(points[1], (hour + 60, 1.));
+
+        let points = backend.fetch_points(&metric, &stages[1], hour, hour + 3600).unwrap();
+        assert_eq!(points, vec![(hour, 29.5)]);
+
+        // One hour of a day is below the xfilesfactor.
+        assert!(backend.fetch_points(&metric, &stages[2], hour, hour + 3600).unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_honours_xfilesfactor() {
+        let backend = MemoryBackend::new();
+        let hour = hour();
+
+        for minute in 0..29 {
+            metric_write(&backend, "app.cpu", 1., hour + minute * 60).unwrap();
+        }
+
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stage = metric.stages().unwrap()[1];
+        assert!(backend.fetch_points(&metric, &stage, hour, hour + 3600).unwrap().is_empty());
+
+        // Half of the hour is known.
+        metric_write(&backend, "app.cpu", 1., hour + 29 * 60).unwrap();
+        assert_eq!(backend.fetch_points(&metric, &stage, hour, hour + 3600).unwrap(), vec![(hour, 1.)]);
+    }
+
+    #[test]
+    fn write_old_points_into_coarser_stages() {
+        let backend = MemoryBackend::new();
+
+        // Beyond the 30 days of the hourly stage.
+        let now = Utc::now().timestamp();
+        let day = now - now.rem_euclid(86400) - 60 * 86400;
+
+        metric_write(&backend, "app.cpu", 1., day + 3600).unwrap();
+
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stages = metric.stages().unwrap();
+
+        assert!(backend.fetch_points(&metric, &stages[0], day, day + 86400).unwrap().is_empty());
+        assert!(backend.fetch_points(&metric, &stages[1], day, day + 86400).unwrap().is_empty());
+        assert_eq!(backend.fetch_points(&metric, &stages[2], day, day + 86400).unwrap(), vec![(day, 1.)]);
+    }
+
+    #[test]
+    fn write_lines_skips_invalid_lines() {
+        let backend = MemoryBackend::new();
+        let hour = hour();
+        let input = format!("app.cpu 1 {}\nnot a point\n\napp.mem 2.5 {}\n", hour, hour + 60);
+
+        metric_write_lines(&backend, &mut input.as_bytes(), 1, 1).unwrap();
+
+        assert_eq!(backend.find_metrics(&["app", "*"]).unwrap(), vec!["app.cpu", "app.mem"]);
+
+        let metric = backend.fetch_metric("app.mem").unwrap();
+        let stage = metric.stages().unwrap()[0];
+        assert_eq!(backend.fetch_points(&metric, &stage, hour, hour + 120).unwrap(), vec![(hour + 60, 2.5)]);
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = MetricCache::new(10);
+
+        for id in 0..10 {
+            cache.insert(Metric::from(format!("app.{}", id)));
+        }
+        assert!(cache.touch("app.0"));
+        assert!(!cache.touch("app.10"));
+
+        cache.insert(Metric::from(String::from("app.10")));
+        cache.trim();
+
+        // One over capacity, plus a tenth of it.
+        assert_eq!(cache.metrics.len(), 9);
+        assert!(cache.get("app.0").is_some());
+        assert!(cache.get("app.10").is_some());
+        assert!(cache.get("app.1").is_none());
+        assert!(cache.get("app.2").is_none());
+        assert!(cache.get("app.3").is_some());
+    }
+}
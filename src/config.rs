@@ -0,0 +1,349 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.0)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+/// Settings read from a TOML configuration file.
+///
+/// Only the subset of TOML needed here is supported: `[table]` headers of
+/// bare keys, `key = value` pairs with bare or quoted keys, string,
+/// integer, float or boolean values, single line arrays of those, and
+/// comments. Inline tables, arrays of tables and multi-line strings or
+/// arrays are not. Keys are stored flattened, as `table.key`; arrays as
+/// their comma separated values.
+///
+/// Named profiles are `[profiles.<name>]` tables; settings of the selected
+/// profile take precedence over top-level ones.
+#[derive(Debug, Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+    profile: Option<String>,
+}
+
+/// `$XDG_CONFIG_HOME/bgutil-rs/config.toml`, or `~/.config/bgutil-rs/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(base.join("bgutil-rs").join("config.toml"))
+}
+
+/// Parse a string after its opening quote, returning it & what follows.
+fn parse_string(value: &str) -> Option<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some((out, chars.as_str())),
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+
+    // Unterminated string.
+    None
+}
+
+/// Parse a string or bare value ending before any of `terminators`,
+/// returning it & what follows.
+fn parse_scalar<'a>(value: &'a str, terminators: &[char]) -> Option<(String, &'a str)> {
+    if let Some(value) = value.strip_prefix('"') {
+        return parse_string(value);
+    }
+
+    let end = value
+        .find(|c: char| c.is_whitespace() || c == '#' || terminators.contains(&c))
+        .unwrap_or(value.len());
+
+    if end == 0 {
+        return None;
+    }
+
+    Some((value[..end].to_string(), &value[end..]))
+}
+
+fn is_end_of_line(rest: &str) -> bool {
+    let rest = rest.trim();
+
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// Parse a value; arrays (on a single line) are joined with commas.
+fn parse_value(value: &str) -> Option<String> {
+    let array = match value.strip_prefix('[') {
+        Some(array) => array,
+        None => {
+            let (value, rest) = parse_scalar(value, &[])?;
+            return if is_end_of_line(rest) { Some(value) } else { None };
+        },
+    };
+
+    let mut values = vec![];
+    let mut rest = array.trim_start();
+
+    // A trailing comma is allowed.
+    while !rest.starts_with(']') {
+        let (value, next) = parse_scalar(rest, &[',', ']'])?;
+        values.push(value);
+
+        rest = next.trim_start();
+        match rest.strip_prefix(',') {
+            Some(next) => rest = next.trim_start(),
+            None if rest.starts_with(']') => break,
+            None => return None,
+        }
+    }
+
+    if is_end_of_line(&rest[1..]) { Some(values.join(",")) } else { None }
+}
+
+/// Parse a bare or quoted key, returning it & what follows `=`.
+fn parse_key(line: &str) -> Option<(String, &str)> {
+    let (key, rest) = match line.strip_prefix('"') {
+        Some(line) => parse_string(line)?,
+        None => {
+            let end = line.find('=')?;
+            let key = line[..end].trim();
+
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                return None;
+            }
+
+            (key.to_string(), &line[end..])
+        }
+    };
+
+    Some((key, rest.trim_start().strip_prefix('=')?.trim()))
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        let mut values = HashMap::new();
+        let mut table = String::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || ConfigError(format!("line {}: {}", number + 1, line));
+
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header.split('#').next().unwrap_or("").trim();
+                table = header.strip_suffix(']').ok_or_else(invalid)?.trim().to_string();
+                continue;
+            }
+
+            let (key, value) = parse_key(line).ok_or_else(invalid)?;
+            let key = if table.is_empty() { key.to_string() } else { format!("{}.{}", table, key) };
+            values.insert(key, parse_value(value).ok_or_else(invalid)?);
+        }
+
+        Ok(Config {
+            values: values,
+            profile: None,
+        })
+    }
+
+    /// Load the given file, or the default one if it exists.
+    pub fn load(path: Option<&str>) -> Result<Self, Box<dyn error::Error>> {
+        let (path, required) = match path {
+            Some(path) => (PathBuf::from(path), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Config::parse(&content)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(err) => Err(ConfigError(format!("{}: {}", path.display(), err)).into()),
+        }
+    }
+
+    /// Select a profile, which must be defined.
+    pub fn set_profile(&mut self, profile: &str) -> Result<(), ConfigError> {
+        let prefix = format!("profiles.{}.", profile);
+
+        if !self.values.keys().any(|key| key.starts_with(&prefix)) {
+            return Err(ConfigError(format!("unknown profile '{}'", profile)));
+        }
+
+        self.profile = Some(profile.to_string());
+
+        Ok(())
+    }
+
+    /// Keys & values of a table, keys relative to it.
+    pub fn table(&self, table: &str) -> Vec<(&str, &str)> {
+        let prefix = format!("{}.", table);
+
+        self.values
+            .iter()
+            .filter_map(|(key, value)| key.strip_prefix(&prefix).map(|key| (key, value.as_str())))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let profile_value = self.profile
+            .as_ref()
+            .and_then(|profile| self.values.get(&format!("profiles.{}.{}", profile, key)));
+
+        profile_value.or_else(|| self.values.get(key)).map(|x| x.as_str())
+    }
+
+    /// Setting of a table such as `[points]`: the profile's `table.key`
+    /// then `key`, before top-level ones.
+    pub fn get_in(&self, table: &str, key: &str) -> Option<&str> {
+        let table_key = format!("{}.{}", table, key);
+
+        let profile_value = self.profile
+            .as_ref()
+            .and_then(|profile| {
+                self.values.get(&format!("profiles.{}.{}", profile, table_key))
+                    .or_else(|| self.values.get(&format!("profiles.{}.{}", profile, key)))
+            });
+
+        profile_value
+            .or_else(|| self.values.get(&table_key))
+            .or_else(|| self.values.get(key))
+            .map(|x| x.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let config = Config::parse(r#"
+# comment
+profile = "local" # trailing comment
+request_timeout = 30
+tls = true
+ratio = 0.5
+escaped = "a \"b\"\tc\\d # not a comment"
+contact_points = ["10.0.0.1", "10.0.0.2",]
+ports = [ 9042 , 9043 ]
+empty = []
+"#).unwrap();
+
+        assert_eq!(config.get("profile"), Some("local"));
+        assert_eq!(config.get("request_timeout"), Some("30"));
+        assert_eq!(config.get("tls"), Some("true"));
+        assert_eq!(config.get("ratio"), Some("0.5"));
+        assert_eq!(config.get("escaped"), Some("a \"b\"\tc\\d # not a comment"));
+        assert_eq!(config.get("contact_points"), Some("10.0.0.1,10.0.0.2"));
+        assert_eq!(config.get("ports"), Some("9042,9043"));
+        assert_eq!(config.get("empty"), Some(""));
+        assert_eq!(config.get("missing"), None);
+    }
+
+    #[test]
+    fn parse_tables_and_quoted_keys() {
+        let config = Config::parse(r#"
+[counters]
+metrics = 3
+"roots.a=b c" = 1
+"[roots]" = 2
+"#).unwrap();
+
+        let mut counters = config.table("counters");
+        counters.sort();
+
+        assert_eq!(counters, vec![("[roots]", "2"), ("metrics", "3"), ("roots.a=b c", "1")]);
+    }
+
+    #[test]
+    fn profiles_take_precedence() {
+        let mut config = Config::parse(r#"
+read_consistency = "one"
+write_consistency = "one"
+
+[profiles.prod]
+read_consistency = "local_quorum"
+"#).unwrap();
+
+        assert!(config.set_profile("staging").is_err());
+        config.set_profile("prod").unwrap();
+
+        assert_eq!(config.get("read_consistency"), Some("local_quorum"));
+        assert_eq!(config.get("write_consistency"), Some("one"));
+    }
+
+    #[test]
+    fn tables_within_profiles() {
+        let mut config = Config::parse(r#"
+username = "top"
+password = "top"
+tls = true
+
+[points]
+username = "top-points"
+password = "top-points"
+
+[profiles.prod]
+username = "prod"
+
+[profiles.prod.metadata]
+username = "prod-metadata"
+"#).unwrap();
+
+        assert_eq!(config.get_in("points", "username"), Some("top-points"));
+        config.set_profile("prod").unwrap();
+
+        assert_eq!(config.get_in("metadata", "username"), Some("prod-metadata"));
+        assert_eq!(config.get_in("points", "username"), Some("prod"));
+        assert_eq!(config.get_in("points", "password"), Some("top-points"));
+        assert_eq!(config.get_in("points", "tls"), Some("true"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for content in &[
+            "key",
+            "= 1",
+            "two words = 1",
+            "key = two words",
+            "key = \"unterminated",
+            "key = \"a\" b",
+            "key = [1, 2",
+            "key = [1 2]",
+            "key = [1] b",
+            "key = { a = 1 }",
+            "[table",
+        ] {
+            assert!(Config::parse(content).is_err(), "{}", content);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::convert::TryFrom;
+
+use crate::json;
+use crate::series::Series;
+
+use chrono::NaiveDateTime;
+
+/// Output formats of read points.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Format {
+    /// `ts;value` lines
+    Semicolon,
+    /// graphite's `name,date,value` lines
+    Csv,
+    /// graphite's render `[{"target": name, "datapoints": [[value, ts], ...]}]`
+    Json,
+    /// graphite's `name,start,end,step|v1,v2,...`, a line per segment
+    Raw,
+}
+
+impl TryFrom<&str> for Format {
+    type Error = &'static str;
+
+    fn try_from(format: &str) -> Result<Self, Self::Error> {
+        match format {
+            "semicolon" => Ok(Format::Semicolon),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "raw" => Ok(Format::Raw),
+            _ => Err("invalid format"),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(self: &Self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Json => "application/json",
+            Format::Semicolon | Format::Raw => "text/plain",
+        }
+    }
+
+    /// Format series; with fill_nulls, missing steps are output as nulls.
+    /// Raw output is always step-aligned.
+    pub fn format(self: &Self, series: &[Series], fill_nulls: bool) -> String {
+        match self {
+            Format::Semicolon => format_semicolon(series, fill_nulls),
+            Format::Csv => format_csv(series, fill_nulls),
+            Format::Json => format_json(series, fill_nulls),
+            Format::Raw => format_raw(series),
+        }
+    }
+}
+
+fn format_semicolon(series: &[Series], fill_nulls: bool) -> String {
+    let mut out = String::new();
+
+    for series in series.iter() {
+        for (ts, value) in series.values(fill_nulls) {
+            match value {
+                Some(value) => out.push_str(&format!("{:?};{:?}\n", ts, value)),
+                None => out.push_str(&format!("{:?};null\n", ts)),
+            }
+        }
+    }
+
+    out
+}
+
+fn format_csv(series: &[Series], fill_nulls: bool) -> String {
+    let mut out = String::new();
+
+    for series in series.iter() {
+        for (ts, value) in series.values(fill_nulls) {
+            let date = NaiveDateTime::from_timestamp(ts, 0);
+            let value = value.map(|v| format!("{:?}", v)).unwrap_or_default();
+
+            out.push_str(&format!("{},{},{}\r\n", series.name, date.format("%Y-%m-%d %H:%M:%S"), value));
+        }
+    }
+
+    out
+}
+
+fn format_json(series: &[Series], fill_nulls: bool) -> String {
+    let series = series
+        .iter()
+        .map(|series| {
+            let datapoints = series.values(fill_nulls)
+                .iter()
+                .map(|(ts, value)| match value {
+                    Some(value) => format!("[{}, {}]", json::number(*value), ts),
+                    None => format!("[null, {}]", ts),
+                })
+                .collect::<Vec<String>>();
+
+            format!("{{\"target\": {}, \"tags\": {{\"name\": {}}}, \"datapoints\": [{}]}}",
+                json::string(&series.name), json::string(&series.name), datapoints.join(", "))
+        })
+        .collect::<Vec<String>>();
+
+    format!("[{}]\n", series.join(", "))
+}
+
+fn format_raw(series: &[Series]) -> String {
+    let mut out = String::new();
+
+    for series in series.iter() {
+        for segment in series.segments.iter() {
+            let slots = segment.slots();
+            let values = slots
+                .iter()
+                .map(|(_, value)| match value {
+                    Some(value) => format!("{:?}", value),
+                    None => String::from("None"),
+                })
+                .collect::<Vec<String>>();
+
+            let start = slots.first().map(|(ts, _)| *ts).unwrap_or(segment.time_start);
+            let end = start + slots.len() as i64 * segment.step();
+
+            out.push_str(&format!("{},{},{},{}|{}\n", series.name, start, end, segment.step(), values.join(",")));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::series::Segment;
+    use crate::stage::Stage;
+
+    // 2020-09-13T12:00:00Z
+    const HOUR : i64 = 1599998400;
+
+    fn series() -> Vec<Series> {
+        vec![Series {
+            name: String::from("app.cpu"),
+            segments: vec![Segment {
+                stage: Stage::try_from("11520*60s").unwrap(),
+                time_start: HOUR,
+                time_end: HOUR + 180,
+                points: vec![(HOUR, 1.), (HOUR + 120, 0.5)],
+            }],
+        }]
+    }
+
+    #[test]
+    fn format_semicolon() {
+        assert_eq!(Format::Semicolon.format(&series(), false), format!("{};1.0\n{};0.5\n", HOUR, HOUR + 120));
+        assert_eq!(Format::Semicolon.format(&series(), true),
+            format!("{};1.0\n{};null\n{};0.5\n", HOUR, HOUR + 60, HOUR + 120));
+    }
+
+    #[test]
+    fn format_csv() {
+        assert_eq!(Format::Csv.format(&series(), true),
+            "app.cpu,2020-09-13 12:00:00,1.0\r\napp.cpu,2020-09-13 12:01:00,\r\napp.cpu,2020-09-13 12:02:00,0.5\r\n");
+    }
+
+    #[test]
+    fn format_json() {
+        assert_eq!(Format::Json.format(&series(), false),
+            format!("[{{\"target\": \"app.cpu\", \"tags\": {{\"name\": \"app.cpu\"}}, \"datapoints\": [[1.0, {}], [0.5, {}]]}}]\n",
+                HOUR, HOUR + 120));
+        assert!(Format::Json.format(&series(), true).contains(&format!("[null, {}]", HOUR + 60)));
+    }
+
+    #[test]
+    fn format_raw() {
+        // Always step-aligned, a line per segment.
+        assert_eq!(Format::Raw.format(&series(), false), format!("app.cpu,{},{},60|1.0,None,0.5\n", HOUR, HOUR + 180));
+    }
+}
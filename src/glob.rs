@@ -0,0 +1,309 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+
+/// Match a dotted name against glob components, client side.
+///
+/// `**` matches any number of components. In a component, `*` matches any
+/// characters, `?` a single one, `[a-z]` (or `[!a-z]`) a character class and
+/// `{a,b,c}` any of the alternatives.
+pub fn glob_match(components: &[&str], name: &str) -> bool {
+    let parts = name.split(".").collect::<Vec<&str>>();
+
+    // Consecutive `**` match like a single one.
+    let mut pattern : Vec<&str> = vec![];
+    for component in components.iter() {
+        if *component != "**" || pattern.last() != Some(&"**") {
+            pattern.push(component);
+        }
+    }
+
+    wildcard_match(&pattern, &parts, |component| *component == "**", |component, part| match_component(component, part))
+}
+
+/// Match `text` against `pattern`, whose stars match any number of items,
+/// other items matching a single one.
+///
+/// Iterative: on mismatch, only the last star is retried one item further,
+/// so matching is at worst in O(pattern * text).
+fn wildcard_match<P, T, S, M>(pattern: &[P], text: &[T], is_star: S, matches: M) -> bool
+    where S: Fn(&P) -> bool,
+          M: Fn(&P, &T) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    // Position after the last star & text position it was retried from.
+    let mut star = None;
+
+    while t < text.len() {
+        if p < pattern.len() && is_star(&pattern[p]) {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if p < pattern.len() && matches(&pattern[p], &text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            star = Some((star_p, star_t + 1));
+            p = star_p;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(is_star)
+}
+
+/// Whether the component has no wildcard at all.
+pub fn is_literal(component: &str) -> bool {
+    !component.contains(&['*', '?', '[', '{'][..])
+}
+
+/// Expand `{a,b}` alternations of a component.
+pub fn expand_braces(component: &str) -> Vec<String> {
+    let start = match component.find('{') {
+        Some(start) => start,
+        None => return vec![component.to_string()],
+    };
+
+    let end = match component[start..].find('}') {
+        Some(end) => start + end,
+        None => return vec![component.to_string()],
+    };
+
+    let head = &component[..start];
+    let tails = expand_braces(&component[end+1..]);
+    let mut out = vec![];
+
+    for alternative in component[start+1..end].split(",") {
+        for tail in tails.iter() {
+            out.push(format!("{}{}{}", head, alternative, tail));
+        }
+    }
+
+    out
+}
+
+/// Literal prefix shared by all names matching the component.
+pub fn literal_prefix(component: &str) -> String {
+    let prefixes = expand_braces(component)
+        .iter()
+        .map(|alternative| {
+            alternative.chars().take_while(|c| !['*', '?', '['].contains(c)).collect::<String>()
+        })
+        .collect::<Vec<String>>();
+
+    let mut prefix = prefixes[0].clone();
+    for other in prefixes[1..].iter() {
+        let common = prefix
+            .chars()
+            .zip(other.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        prefix.truncate(common);
+    }
+
+    prefix
+}
+
+fn match_component(component: &str, part: &str) -> bool {
+    let part = part.chars().collect::<Vec<char>>();
+
+    expand_braces(component)
+        .iter()
+        .any(|alternative| {
+            wildcard_match(&tokenize(alternative), &part, |token| matches!(token, Token::Star), |token, c| token.matches(*c))
+        })
+}
+
+struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+    // length up to & including `]`
+    len: usize,
+}
+
+impl CharClass {
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|(low, high)| *low <= c && c <= *high) != self.negated
+    }
+}
+
+/// Parse a `[...]` class content, after `[`.
+fn parse_class(pattern: &[char]) -> Option<CharClass> {
+    let mut pos = 0;
+    let negated = match pattern.first() {
+        Some('!') | Some('^') => {
+            pos += 1;
+            true
+        },
+        _ => false,
+    };
+
+    let mut ranges = vec![];
+    let first = pos;
+
+    loop {
+        let c = *pattern.get(pos)?;
+
+        // A leading `]` is a literal one.
+        if c == ']' && pos != first {
+            return Some(CharClass {
+                ranges: ranges,
+                negated: negated,
+                len: pos + 1,
+            });
+        }
+
+        if pattern.get(pos + 1) == Some(&'-') && !matches!(pattern.get(pos + 2), None | Some(']')) {
+            ranges.push((c, pattern[pos + 2]));
+            pos += 3;
+        } else {
+            ranges.push((c, c));
+            pos += 1;
+        }
+    }
+}
+
+/// Item of a component pattern, once braces are expanded.
+enum Token {
+    Star,
+    Any,
+    Class(CharClass),
+    Char(char),
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Star | Token::Any => true,
+            Token::Class(class) => class.contains(c),
+            Token::Char(expected) => *expected == c,
+        }
+    }
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let mut tokens = vec![];
+    let mut pos = 0;
+
+    while pos < pattern.len() {
+        match pattern[pos] {
+            // Consecutive stars match like a single one.
+            '*' => {
+                if !matches!(tokens.last(), Some(Token::Star)) {
+                    tokens.push(Token::Star);
+                }
+            },
+            '?' => tokens.push(Token::Any),
+            '[' => {
+                match parse_class(&pattern[pos+1..]) {
+                    Some(class) => {
+                        pos += class.len;
+                        tokens.push(Token::Class(class));
+                    },
+                    // Unterminated class: literal `[`.
+                    None => tokens.push(Token::Char('[')),
+                }
+            },
+            c => tokens.push(Token::Char(c)),
+        }
+
+        pos += 1;
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        glob_match(&pattern.split(".").collect::<Vec<&str>>(), name)
+    }
+
+    #[test]
+    fn match_components() {
+        assert!(matches("app.cpu", "app.cpu"));
+        assert!(!matches("app.cpu", "app.cpu.user"));
+        assert!(!matches("app.cpu.user", "app.cpu"));
+        assert!(matches("app.*", "app.cpu"));
+        assert!(!matches("app.*", "app.cpu.user"));
+    }
+
+    #[test]
+    fn match_globstar() {
+        assert!(matches("**", "app"));
+        assert!(matches("app.**", "app.cpu.user"));
+        assert!(matches("app.**.user", "app.user"));
+        assert!(matches("app.**.user", "app.cpu.core0.user"));
+        assert!(!matches("app.**.user", "app.cpu.system"));
+        assert!(matches("**.**.user", "app.cpu.user"));
+        assert!(matches("**.cpu.**.user", "a.b.cpu.c.user"));
+        assert!(!matches("**.cpu.**.user", "a.b.cpu"));
+    }
+
+    #[test]
+    fn match_wildcards() {
+        assert!(matches("app.c*u", "app.cpu"));
+        assert!(matches("app.c*u", "app.cu"));
+        assert!(matches("app.***", "app.cpu"));
+        assert!(matches("app.c?u", "app.cpu"));
+        assert!(!matches("app.c?u", "app.cu"));
+        assert!(matches("app.*p*", "app.cpu"));
+        assert!(!matches("app.*x*", "app.cpu"));
+    }
+
+    #[test]
+    fn match_character_classes() {
+        assert!(matches("host[0-9]", "host1"));
+        assert!(!matches("host[0-9]", "hosta"));
+        assert!(matches("host[!0-9]", "hosta"));
+        assert!(matches("host[^0-9]", "hosta"));
+        assert!(matches("host[ab-]", "host-"));
+        assert!(matches("host[]]", "host]"));
+        // Unterminated class: literal `[`.
+        assert!(matches("host[0", "host[0"));
+    }
+
+    #[test]
+    fn match_braces() {
+        assert!(matches("app.{cpu,mem}", "app.mem"));
+        assert!(!matches("app.{cpu,mem}", "app.disk"));
+        assert!(matches("app.{cpu,mem}.*", "app.cpu.user"));
+    }
+
+    #[test]
+    fn match_is_not_exponential() {
+        let name = vec!["a"; 40].join(".");
+        let pattern = format!("{}.b", vec!["**.a"; 20].join("."));
+        assert!(!matches(&pattern, &name));
+
+        let component = format!("{}b", "*a".repeat(20));
+        assert!(!matches(&component, &"a".repeat(60)));
+    }
+
+    #[test]
+    fn brace_expansion() {
+        assert_eq!(expand_braces("cpu"), vec!["cpu"]);
+        assert_eq!(expand_braces("{cpu,mem}"), vec!["cpu", "mem"]);
+        assert_eq!(expand_braces("host{1,2}.{a,b}"), vec!["host1.a", "host1.b", "host2.a", "host2.b"]);
+        assert_eq!(expand_braces("host{1,2"), vec!["host{1,2"]);
+    }
+
+    #[test]
+    fn literal_prefixes() {
+        assert_eq!(literal_prefix("cpu"), "cpu");
+        assert_eq!(literal_prefix("cpu*"), "cpu");
+        assert_eq!(literal_prefix("c?u"), "c");
+        assert_eq!(literal_prefix("host[0-9]"), "host");
+        assert_eq!(literal_prefix("{host1,host2}"), "host");
+        assert_eq!(literal_prefix("{cpu,mem}"), "");
+        assert!(is_literal("cpu"));
+        assert!(!is_literal("cpu*"));
+    }
+}
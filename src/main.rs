@@ -4,29 +4,54 @@
  * Author: Patrick MARIE <pm@mkz.me>
  */
 use std::convert::TryFrom;
+use std::str::FromStr;
 use std::error;
+use std::fs::File;
+use std::io::{self,BufReader};
+use std::sync::Arc;
 
-use cassandra_cpp::CassResult;
+use cassandra_cpp::{CassResult,Consistency};
 use chrono::Utc;
-use clap::{App,AppSettings,Arg,SubCommand};
+use clap::{App,AppSettings,Arg,ArgMatches,SubCommand};
 
+mod aggregator;
+mod backend;
 mod cassandra;
+mod config;
+mod format;
+mod glob;
+mod json;
+#[cfg(test)]
+mod memory;
 mod metric;
+mod policy;
+mod scan;
+mod series;
 mod session;
 mod stage;
+mod timeparse;
 mod timerange;
 mod cmd;
 
-use crate::cassandra::*;
+use crate::backend::Backend;
+use crate::cassandra::{ClusterOptions,FindStrategy,Retry,TlsOptions};
+use crate::config::Config;
+use crate::format::Format;
 use crate::session::Session;
-use crate::stage::Stage;
 use crate::metric::Metric;
+use crate::policy::RetentionPolicy;
+use crate::scan::ScanOptions;
+use crate::stage::Stage;
+use crate::timeparse::{parse_duration,parse_time};
 
+use crate::cmd::carbon::*;
 use crate::cmd::clean::*;
 use crate::cmd::delete::*;
 use crate::cmd::info::*;
 use crate::cmd::list::*;
 use crate::cmd::local_clean::*;
+use crate::cmd::read::*;
+use crate::cmd::serve::*;
 use crate::cmd::stats::*;
 use crate::cmd::write::*;
 
@@ -43,109 +68,444 @@ fn describe_result(result: &CassResult) {
     }
 }
 
-fn main() -> Result<(), Box<dyn error::Error>> {
-    let matches = App::new("bgutil-rs")
-                           .setting(AppSettings::SubcommandRequired)
-                           .arg(Arg::with_name("contact-metadata")
-                                .long("contact-metadata")
-                                .env("CASSANDRA_CONTACT_METADATA")
-                                .takes_value(true))
-                           .arg(Arg::with_name("contact-points")
-                                .long("contact-points")
-                                .env("CASSANDRA_CONTACT_POINTS")
-                                .takes_value(true))
-                           .arg(Arg::with_name("dry-run")
-                                .help("Do not write in database (local-clean only)")
-                                .long("dry-run"))
-                           .subcommand(SubCommand::with_name("info")
-                                       .about("Information about a metric")
-                                       .arg(Arg::with_name("metric")
-                                            .help("metric to retrieve info about")
-                                            .index(1)
-                                            .required(true)))
-                           .subcommand(SubCommand::with_name("read")
-                                       .about("Read a metric contents")
-                                       .arg(Arg::with_name("stage")
-                                            .long("stage")
-                                            .takes_value(true))
-                                       .arg(Arg::with_name("time-start")
-                                            .long("time-start")
-                                            .takes_value(true))
-                                       .arg(Arg::with_name("time-end")
-                                            .long("time-end")
-                                            .takes_value(true))
-                                       .arg(Arg::with_name("metric")
-                                            .help("metric to get values")
-                                            .index(1)
-                                            .required(true)))
-                           .subcommand(SubCommand::with_name("list")
-                                       .about("List metrics with given pattern")
-                                       .arg(Arg::with_name("glob")
-                                            .index(1)
-                                            .required(true)))
-                           .subcommand(SubCommand::with_name("write")
-                                       .about("Write a metric and its value")
-                                       .arg(Arg::with_name("metric")
-                                            .index(1)
-                                            .required(true))
-                                       .arg(Arg::with_name("value")
-                                            .index(2)
-                                            .required(true))
-                                       .arg(Arg::with_name("timestamp")
-                                            .short("t")
-                                            .long("timestamp")
-                                            .takes_value(true))
-                                       .arg(Arg::with_name("retention")
-                                            .long("retention")
-                                            .takes_value(true)))
-                           .subcommand(SubCommand::with_name("delete")
-                                       .about("Delete metric(s)")
-                                       .arg(Arg::with_name("recursive")
-                                            .long("recursive"))
-                                       .arg(Arg::with_name("metric")
-                                            .index(1)
-                                            .required(true)))
-                           .subcommand(SubCommand::with_name("stats")
-                                        .about("Stats")
-                                        .arg(Arg::with_name("start-key")
-                                             .long("start-key")
-                                             .takes_value(true))
-                                        .arg(Arg::with_name("end-key")
-                                             .long("end-key")
-                                             .takes_value(true)))
-                           .subcommand(SubCommand::with_name("clean")
-                                        .about("Clean outdated metrics & empty directories")
-                                        .arg(Arg::with_name("start-key")
-                                             .long("start-key")
-                                             .takes_value(true))
-                                        .arg(Arg::with_name("end-key")
-                                             .long("end-key")
-                                             .takes_value(true))
-                                        .arg(Arg::with_name("clean-metrics")
-                                             .long("clean-metrics"))
-                                        .arg(Arg::with_name("clean-directories")
-                                             .long("clean-directories")))
-                           .subcommand(SubCommand::with_name("local-clean")
-                                        .about("Clean a directory of outdated metrics & empty sub-directories")
-                                        .arg(Arg::with_name("directory")
-                                             .index(1)
-                                             .required(true)))
-                           .get_matches();
-
-    let mut contact_points_metadata = "localhost";
-    if matches.is_present("contact-metadata") {
-        contact_points_metadata = matches.value_of("contact-metadata").unwrap();
+/// Connection options of the metadata or points cluster. Options apply to
+/// both; configuration keys can be set for one only in a `[metadata]` or
+/// `[points]` table. Options take precedence over the profile, itself over
+/// top-level keys.
+fn cluster_options(matches: &ArgMatches, config: &Config, cluster: &str) -> Result<ClusterOptions, Box<dyn error::Error>> {
+    let config_setting = |key: &str| config.get_in(cluster, key);
+    let setting = |name: &str, key: &str| matches.value_of(name).or_else(|| config_setting(key));
+
+    let mut options = ClusterOptions::default();
+    if let Some(retry) = setting("retry-policy", "retry_policy") {
+        options.retry = Retry::try_from(retry)?;
+    }
+    if let Some(request_timeout) = setting("request-timeout", "request_timeout") {
+        options.request_timeout = request_timeout.parse::<i64>()?;
+    }
+    if let Some(protocol_version) = setting("protocol-version", "protocol_version") {
+        options.protocol_version = protocol_version.parse::<i32>()?;
     }
 
-    let mut contact_points_data = "localhost";
-    if matches.is_present("contact-points") {
-        contact_points_data = matches.value_of("contact-points").unwrap();
+    if let Some(username) = setting("username", "username") {
+        let password = setting("password", "password").unwrap_or("");
+        options.credentials = Some((username.to_string(), password.to_string()));
     }
 
-    let dry_run = matches.is_present("dry-run");
+    if matches.is_present("tls") || config_setting("tls") == Some("true") {
+        let verify = setting("tls-verify", "tls_verify").unwrap_or("identity");
+
+        options.tls = Some(TlsOptions {
+            ca_cert: setting("tls-ca-cert", "tls_ca_cert").map(String::from),
+            cert: setting("tls-cert", "tls_cert").map(String::from),
+            key: setting("tls-key", "tls_key").map(String::from),
+            key_password: setting("tls-key-password", "tls_key_password").unwrap_or("").to_string(),
+            verify_cert: verify != "none",
+            verify_identity: verify == "identity",
+        });
+    }
+
+    Ok(options)
+}
+
+/// Consistency level of an option, or its configuration key.
+fn consistency(matches: &ArgMatches, config: &Config, name: &str, key: &str) -> Result<Consistency, Box<dyn error::Error>> {
+    let level = matches.value_of(name).or(config.get(key)).unwrap_or("local_quorum");
+
+    Ok(Consistency::from_str(&level.to_uppercase())?)
+}
 
-    let mut session = Session::new(&contact_points_metadata, &contact_points_data)?;
+/// Retention policy of clean & local-clean, from options or configuration.
+fn retention_policy(matches: &ArgMatches, config: &Config) -> Result<RetentionPolicy, Box<dyn error::Error>> {
+    let max_age = parse_duration(matches.value_of("max-age").or(config.get("clean_max_age")).unwrap_or("14d"))?;
+
+    match matches.value_of("policy").or(config.get("clean_policy")) {
+        Some(path) => RetentionPolicy::load(path, max_age),
+        None => Ok(RetentionPolicy::new(max_age)),
+    }
+}
+
+fn scan_options(matches: &ArgMatches) -> Result<ScanOptions, Box<dyn error::Error>> {
+    let workers = matches.value_of("workers").unwrap().parse::<usize>()?;
+    let rate = match matches.value_of("rate-limit") {
+        Some(rate) => Some(rate.parse::<u64>()?),
+        None => None,
+    };
+
+    let (state_file, resume) = match matches.value_of("resume") {
+        Some(path) => (Some(path.to_string()), true),
+        None => (matches.value_of("state-file").map(String::from), false),
+    };
+
+    Ok(ScanOptions {
+        workers: workers,
+        rate: rate,
+        state_file: state_file,
+        resume: resume,
+    })
+}
+
+/// Command line of bgutil-rs.
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("bgutil-rs")
+             .setting(AppSettings::SubcommandRequired)
+             .after_help("Connection options (credentials, TLS, retry policy, timeout & protocol version) apply to both the \
+                          metadata & points clusters: to set one of them only, use a [metadata] or [points] table of the \
+                          configuration file.")
+             .arg(Arg::with_name("config")
+                  .help("Configuration file (default: ~/.config/bgutil-rs/config.toml)")
+                  .long("config")
+                  .env("BGUTIL_CONFIG")
+                  .takes_value(true))
+             .arg(Arg::with_name("profile")
+                  .help("Profile of the configuration file to use")
+                  .long("profile")
+                  .env("BGUTIL_PROFILE")
+                  .takes_value(true))
+             .arg(Arg::with_name("contact-metadata")
+                  .long("contact-metadata")
+                  .env("CASSANDRA_CONTACT_METADATA")
+                  .takes_value(true))
+             .arg(Arg::with_name("contact-points")
+                  .long("contact-points")
+                  .env("CASSANDRA_CONTACT_POINTS")
+                  .takes_value(true))
+             .arg(Arg::with_name("username")
+                  .help("Username to authenticate with")
+                  .long("username")
+                  .env("CASSANDRA_USERNAME")
+                  .takes_value(true))
+             .arg(Arg::with_name("password")
+                  .help("Password to authenticate with")
+                  .long("password")
+                  .env("CASSANDRA_PASSWORD")
+                  .hide_env_values(true)
+                  .takes_value(true))
+             .arg(Arg::with_name("tls")
+                  .help("Connect using TLS")
+                  .long("tls"))
+             .arg(Arg::with_name("tls-ca-cert")
+                  .help("CA certificate (PEM) to verify servers with")
+                  .long("tls-ca-cert")
+                  .takes_value(true))
+             .arg(Arg::with_name("tls-cert")
+                  .help("Client certificate (PEM)")
+                  .long("tls-cert")
+                  .takes_value(true))
+             .arg(Arg::with_name("tls-key")
+                  .help("Client private key (PEM)")
+                  .long("tls-key")
+                  .takes_value(true))
+             .arg(Arg::with_name("tls-key-password")
+                  .help("Password of the client private key")
+                  .long("tls-key-password")
+                  .takes_value(true))
+             .arg(Arg::with_name("tls-verify")
+                  .help("Server verification: none, certificate only, or certificate & address identity (default: identity)")
+                  .long("tls-verify")
+                  .possible_values(&["none", "cert", "identity"])
+                  .takes_value(true))
+             .arg(Arg::with_name("metadata-keyspace")
+                  .help("Keyspace of metrics metadata (default: biggraphite_metadata)")
+                  .long("metadata-keyspace")
+                  .env("BIGGRAPHITE_METADATA_KEYSPACE")
+                  .takes_value(true))
+             .arg(Arg::with_name("points-keyspace")
+                  .help("Keyspace of points (default: biggraphite)")
+                  .long("points-keyspace")
+                  .env("BIGGRAPHITE_POINTS_KEYSPACE")
+                  .takes_value(true))
+             .arg(Arg::with_name("read-consistency")
+                  .help("Consistency level of reads (default: local_quorum)")
+                  .long("read-consistency")
+                  .takes_value(true))
+             .arg(Arg::with_name("write-consistency")
+                  .help("Consistency level of writes (default: local_quorum)")
+                  .long("write-consistency")
+                  .takes_value(true))
+             .arg(Arg::with_name("retry-policy")
+                  .help("Retry policy of requests (default: downgrading)")
+                  .long("retry-policy")
+                  .possible_values(&["default", "downgrading", "fallthrough"])
+                  .takes_value(true))
+             .arg(Arg::with_name("request-timeout")
+                  .help("Request timeout, in seconds (default: 30)")
+                  .long("request-timeout")
+                  .takes_value(true))
+             .arg(Arg::with_name("protocol-version")
+                  .help("CQL protocol version (default: 4)")
+                  .long("protocol-version")
+                  .takes_value(true))
+             .arg(Arg::with_name("read-parallelism")
+                  .help("Maximum number of concurrent partition queries per read")
+                  .long("read-parallelism")
+                  .default_value("16")
+                  .takes_value(true))
+             .arg(Arg::with_name("max-components")
+                  .help("Maximum number of components in a metric name (default: discovered from schema). A '**' pattern costs an ALLOW FILTERING query per possible length: lower it if names are shorter")
+                  .long("max-components")
+                  .validator(|value| match value.parse::<usize>() {
+                      Ok(n) if n > 0 => Ok(()),
+                      _ => Err(String::from("expected a number of components, at least 1")),
+                  })
+                  .takes_value(true))
+             .arg(Arg::with_name("find-strategy")
+                  .help("How patterns are resolved: walking directories or filtering components ('auto' walks patterns starting with a literal and with few wildcards, filters others)")
+                  .long("find-strategy")
+                  .possible_values(&["auto", "walk", "filter"])
+                  .default_value("auto")
+                  .takes_value(true))
+             .arg(Arg::with_name("dry-run")
+                  .help("Do not write in database (clean, local-clean & delete --recursive only)")
+                  .long("dry-run"))
+             .subcommand(SubCommand::with_name("info")
+                         .about("Information about a metric")
+                         .arg(Arg::with_name("metric")
+                              .help("metric to retrieve info about")
+                              .index(1)
+                              .required(true)))
+             .subcommand(SubCommand::with_name("read")
+                         .about("Read a metric contents")
+                         .arg(Arg::with_name("stage")
+                              .help("Stage to read from, 'stitch' the finest one of each period, 'auto' only the finest one covering time-start (default: stitch)")
+                              .long("stage")
+                              .takes_value(true))
+                         .arg(Arg::with_name("format")
+                              .help("Output format (default: semicolon)")
+                              .long("format")
+                              .possible_values(&["semicolon", "csv", "json", "raw"])
+                              .takes_value(true))
+                         .arg(Arg::with_name("fill-nulls")
+                              .help("Output every step of the range, with nulls for missing points")
+                              .long("fill-nulls"))
+                         .arg(Arg::with_name("time-start")
+                              .help("Timestamp, date or graphite time (-1h, yesterday...) [default: -1h]")
+                              .long("time-start")
+                              .takes_value(true))
+                         .arg(Arg::with_name("time-end")
+                              .help("Timestamp, date or graphite time (now, -5min...) [default: time-start + 1h]")
+                              .long("time-end")
+                              .takes_value(true))
+                         .arg(Arg::with_name("metric")
+                              .help("metric to get values")
+                              .index(1)
+                              .required(true)))
+             .subcommand(SubCommand::with_name("list")
+                         .about("List metrics with given pattern")
+                         .arg(Arg::with_name("format")
+                              .help("Output format")
+                              .long("format")
+                              .possible_values(&["text", "json", "jsonl", "names"])
+                              .default_value("text")
+                              .takes_value(true))
+                         .arg(Arg::with_name("only")
+                              .help("Only list metrics or directories")
+                              .long("only")
+                              .possible_values(&["metrics", "directories"])
+                              .takes_value(true))
+                         .arg(Arg::with_name("count")
+                              .help("Only output the number of matching entries")
+                              .long("count"))
+                         .arg(Arg::with_name("tree")
+                              .help("Show matching directories and their contents as a tree")
+                              .long("tree")
+                              .conflicts_with("count"))
+                         .arg(Arg::with_name("max-depth")
+                              .help("Maximum depth shown below matching directories (--tree only)")
+                              .long("max-depth")
+                              .requires("tree")
+                              .takes_value(true))
+                         .arg(Arg::with_name("glob")
+                              .index(1)
+                              .required(true)))
+             .subcommand(SubCommand::with_name("write")
+                         .about("Write a metric and its value")
+                         .arg(Arg::with_name("metric")
+                              .index(1)
+                              .required_unless("from-file"))
+                         .arg(Arg::with_name("value")
+                              .index(2)
+                              .required_unless("from-file"))
+                         .arg(Arg::with_name("from-file")
+                              .help("Write 'metric value timestamp' lines from file, or stdin with '-'")
+                              .long("from-file")
+                              .conflicts_with_all(&["metric", "value", "timestamp"])
+                              .takes_value(true))
+                         .arg(Arg::with_name("parallelism")
+                              .help("Maximum number of concurrent inserts (with --from-file)")
+                              .long("parallelism")
+                              .default_value("64")
+                              .takes_value(true))
+                         .arg(Arg::with_name("cache-size")
+                              .help("Maximum number of metrics kept in cache (with --from-file)")
+                              .long("cache-size")
+                              .default_value("100000")
+                              .takes_value(true))
+                         .arg(Arg::with_name("timestamp")
+                              .help("Timestamp, date or graphite time (now, -5min...) [default: now]")
+                              .short("t")
+                              .long("timestamp")
+                              .takes_value(true)))
+             .subcommand(SubCommand::with_name("delete")
+                         .about("Delete metric(s)")
+                         .arg(Arg::with_name("recursive")
+                              .help("Delete all metrics & directories under the given prefix")
+                              .long("recursive"))
+                         .arg(Arg::with_name("metric")
+                              .index(1)
+                              .required(true)))
+             .subcommand(SubCommand::with_name("stats")
+                          .about("Stats")
+                          .arg(Arg::with_name("start-key")
+                               .long("start-key")
+                               .takes_value(true))
+                          .arg(Arg::with_name("end-key")
+                               .long("end-key")
+                               .takes_value(true))
+                          .arg(Arg::with_name("workers")
+                               .help("Number of token sub-ranges scanned concurrently")
+                               .long("workers")
+                               .default_value("1")
+                               .takes_value(true))
+                          .arg(Arg::with_name("rate-limit")
+                               .help("Maximum number of queries per second, all workers included")
+                               .long("rate-limit")
+                               .takes_value(true))
+                          .arg(Arg::with_name("state-file")
+                               .help("Save the scan state to this file, periodically & on error or Ctrl-C")
+                               .long("state-file")
+                               .takes_value(true))
+                          .arg(Arg::with_name("resume")
+                               .help("Resume the scan saved in this state file, and keep saving it there")
+                               .long("resume")
+                               .conflicts_with_all(&["state-file", "start-key", "end-key"])
+                               .takes_value(true)))
+             .subcommand(SubCommand::with_name("clean")
+                          .about("Clean outdated metrics & empty directories")
+                          .arg(Arg::with_name("start-key")
+                               .long("start-key")
+                               .takes_value(true))
+                          .arg(Arg::with_name("end-key")
+                               .long("end-key")
+                               .takes_value(true))
+                          .arg(Arg::with_name("clean-metrics")
+                               .long("clean-metrics"))
+                          .arg(Arg::with_name("clean-directories")
+                               .long("clean-directories"))
+                          .arg(Arg::with_name("workers")
+                               .help("Number of token sub-ranges scanned concurrently")
+                               .long("workers")
+                               .default_value("1")
+                               .takes_value(true))
+                          .arg(Arg::with_name("rate-limit")
+                               .help("Maximum number of queries per second, all workers included")
+                               .long("rate-limit")
+                               .takes_value(true))
+                          .arg(Arg::with_name("state-file")
+                               .help("Save the scan state to this file, periodically & on error or Ctrl-C")
+                               .long("state-file")
+                               .takes_value(true))
+                          .arg(Arg::with_name("resume")
+                               .help("Resume the scan saved in this state file, and keep saving it there")
+                               .long("resume")
+                               .conflicts_with_all(&["state-file", "start-key", "end-key"])
+                               .takes_value(true))
+                          .arg(Arg::with_name("max-age")
+                               .help("Age after which a metric not updated is outdated (default: 14d)")
+                               .long("max-age")
+                               .takes_value(true))
+                          .arg(Arg::with_name("policy")
+                               .help("File of '<glob> <max age>' rules, overriding --max-age for matching metrics")
+                               .long("policy")
+                               .takes_value(true)))
+             .subcommand(SubCommand::with_name("local-clean")
+                          .about("Clean a directory of outdated metrics & empty sub-directories")
+                          .arg(Arg::with_name("max-age")
+                               .help("Age after which a metric not updated is outdated (default: 14d)")
+                               .long("max-age")
+                               .takes_value(true))
+                          .arg(Arg::with_name("policy")
+                               .help("File of '<glob> <max age>' rules, overriding --max-age for matching metrics")
+                               .long("policy")
+                               .takes_value(true))
+                          .arg(Arg::with_name("directory")
+                               .index(1)
+                               .required(true)))
+             .subcommand(SubCommand::with_name("serve")
+                          .about("Serve graphite-web compatible /metrics/find & /render API")
+                          .arg(Arg::with_name("listen")
+                               .long("listen")
+                               .default_value("127.0.0.1:8080")
+                               .takes_value(true))
+                          .arg(Arg::with_name("workers")
+                               .help("Number of requests handled concurrently")
+                               .long("workers")
+                               .default_value("8")
+                               .takes_value(true)))
+             .subcommand(SubCommand::with_name("carbon-listen")
+                          .about("Receive graphite plaintext protocol (tcp & udp) and write points")
+                          .arg(Arg::with_name("listen")
+                               .long("listen")
+                               .default_value("127.0.0.1:2003")
+                               .takes_value(true))
+                          .arg(Arg::with_name("queue-size")
+                               .help("Maximum number of points waiting to be written")
+                               .long("queue-size")
+                               .default_value("100000")
+                               .takes_value(true))
+                          .arg(Arg::with_name("batch-size")
+                               .help("Maximum number of points written at once")
+                               .long("batch-size")
+                               .default_value("1000")
+                               .takes_value(true))
+                          .arg(Arg::with_name("cache-size")
+                               .help("Maximum number of metrics kept in cache")
+                               .long("cache-size")
+                               .default_value("100000")
+                               .takes_value(true)))
+}
+
+fn main() -> Result<(), Box<dyn error::Error>> {
+    let matches = app().get_matches();
+
+    let mut config = Config::load(matches.value_of("config"))?;
+    if let Some(profile) = matches.value_of("profile").or(config.get("profile")).map(String::from) {
+        config.set_profile(&profile)?;
+    }
+
+    // Options & environment take precedence over the configuration file.
+    let setting = |name: &str, key: &str| matches.value_of(name).or(config.get(key));
+
+    let contact_points_metadata = setting("contact-metadata", "contact_metadata").unwrap_or("localhost");
+    let contact_points_data = setting("contact-points", "contact_points").unwrap_or("localhost");
+
+    let dry_run = matches.is_present("dry-run");
+    let read_parallelism = matches.value_of("read-parallelism").unwrap().parse::<usize>()?;
+
+    let mut session = Session::new(
+        &contact_points_metadata,
+        &contact_points_data,
+        &cluster_options(&matches, &config, "metadata")?,
+        &cluster_options(&matches, &config, "points")?,
+    )?;
     session.set_dry_run(dry_run);
+    session.set_read_parallelism(read_parallelism);
+
+    session.set_keyspaces(
+        setting("metadata-keyspace", "metadata_keyspace").unwrap_or("biggraphite_metadata"),
+        setting("points-keyspace", "points_keyspace").unwrap_or("biggraphite"),
+    );
+
+    session.set_consistency(
+        consistency(&matches, &config, "read-consistency", "read_consistency")?,
+        consistency(&matches, &config, "write-consistency", "write_consistency")?,
+    );
+
+    session.set_find_strategy(FindStrategy::try_from(matches.value_of("find-strategy").unwrap())?);
+
+    if let Some(max_components) = matches.value_of("max-components") {
+        session.set_max_components(max_components.parse::<usize>()?);
+    }
 
     match matches.subcommand_name() {
         Some("info") => {
@@ -154,79 +514,95 @@ fn main() -> Result<(), Box<dyn error::Error>> {
         },
         Some("read") => {
             let matches = matches.subcommand_matches("read").unwrap();
-            let stage = matches.value_of("stage").unwrap_or("11520*60s");
+            let stage = matches.value_of("stage").or(config.get("read_stage")).unwrap_or("stitch");
             // XXX: Change default value relative to stage's precision to have more or less data
-            let time_start = matches.value_of("time-start"); // default now - 1h
-            let time_end = matches.value_of("time-end"); // default: now
+            let time_start = matches.value_of("time-start");
+            let time_end = matches.value_of("time-end");
+
+            let now = Utc::now().timestamp();
 
             let time_start = match time_start {
-                None => Utc::now().timestamp() - 3600,
-                Some(s) => match s.parse::<i64>() {
-                    Ok(n) => n,
-                    Err(_) => {
-                        eprintln!("Could not parse {}", s);
-                        return Ok(())
-                    }
-                }
+                None => now - 3600,
+                Some(s) => parse_time(s, now)?,
             };
 
             let time_end = match time_end {
                 None => time_start + 3600,
-                Some(s) => match s.parse::<i64>() {
-                    Ok(n) => n,
-                    Err(_) => {
-                        eprintln!("Could not parse {}", s);
-                        return Ok(())
-                    }
-                }
+                Some(s) => parse_time(s, now)?,
             };
 
             let metric_name = matches.value_of("metric").unwrap();
-            let metric = fetch_metric(&session, metric_name)?;
 
-            let available_stages = metric.stages()?;
-            let stage = Stage::try_from(stage)?;
+            let format = Format::try_from(matches.value_of("format").or(config.get("read_format")).unwrap_or("semicolon"))?;
 
-            if !available_stages.iter().any(|x| *x == stage) {
-                eprintln!("Could not find any stage matching {}", stage);
-                return Ok(());
-            }
+            let fill_nulls = matches.is_present("fill-nulls");
 
-            fetch_points(&session, &metric, &stage, time_start, time_end)?;
+            metric_read(&session, metric_name, stage, time_start, time_end, &format, fill_nulls)?;
         },
         Some("list") => {
             let matches = matches.subcommand_matches("list").unwrap();
-            metric_list(&session, matches.value_of("glob").unwrap())?;
+            let format = ListFormat::try_from(matches.value_of("format").unwrap())?;
+            let only = matches.value_of("only");
+
+            if matches.is_present("tree") {
+                if only == Some("metrics") {
+                    return Err("--tree cannot only show metrics, use --only directories or none".into());
+                }
+
+                let max_depth = match matches.value_of("max-depth") {
+                    Some(max_depth) => Some(max_depth.parse::<usize>()?),
+                    None => None,
+                };
+
+                metric_tree(&session, matches.value_of("glob").unwrap(), max_depth, only != Some("directories"))?;
+            } else {
+                metric_list(&session,
+                    matches.value_of("glob").unwrap(),
+                    format,
+                    only != Some("metrics"),
+                    only != Some("directories"),
+                    matches.is_present("count"),
+                )?;
+            }
         },
         Some("write") => {
             let matches = matches.subcommand_matches("write").unwrap();
 
+            if let Some(path) = matches.value_of("from-file") {
+                let parallelism = matches.value_of("parallelism").unwrap().parse::<usize>()?;
+                let cache_size = matches.value_of("cache-size").unwrap().parse::<usize>()?;
+
+                if path == "-" {
+                    let stdin = io::stdin();
+                    metric_write_lines(&session, &mut stdin.lock(), parallelism, cache_size)?;
+                } else {
+                    let mut reader = BufReader::new(File::open(path)?);
+                    metric_write_lines(&session, &mut reader, parallelism, cache_size)?;
+                }
+
+                return Ok(());
+            }
+
             let metric = matches.value_of("metric").unwrap();
             let value = matches.value_of("value").unwrap().parse::<f64>()?;
 
-            let retention = matches.value_of("retention").unwrap_or("11520*60s");
+            let now = Utc::now().timestamp();
             let timestamp = match matches.value_of("timestamp") {
-                None => Utc::now().timestamp(),
-                Some(s) => match s.parse::<i64>() {
-                    Ok(n) => n,
-                    Err(_) => {
-                        eprintln!("Could not parse {}", s);
-                        return Ok(())
-                    }
-                }
+                None => now,
+                Some(s) => parse_time(s, now)?,
             };
 
-            metric_write(&session, metric, value, retention, timestamp)?;
+            metric_write(&session, metric, value, timestamp)?;
         },
         Some("delete") => {
             let matches = matches.subcommand_matches("delete").unwrap();
             let metric = matches.value_of("metric").unwrap();
 
             if matches.is_present("recursive") {
-                unimplemented!();
+                metric_delete_recursive(&session, &metric)?;
+            } else {
+                metric_delete(&session, &metric)?;
             }
-
-            metric_delete(&session, &metric)?;
         },
         Some("stats") => {
             let matches = matches.subcommand_matches("stats").unwrap();
@@ -255,7 +631,9 @@ fn main() -> Result<(), Box<dyn error::Error>> {
                 }
             };
 
-            metric_stats(&session, start_key, end_key)?;
+            let options = scan_options(matches)?;
+
+            metric_stats(&session, start_key, end_key, &options)?;
         },
         Some("clean") => {
             let matches = matches.subcommand_matches("clean").unwrap();
@@ -288,14 +666,36 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             let clean_metrics = matches.is_present("clean-metrics");
             let clean_directories = matches.is_present("clean-directories");
 
-            metrics_clean(&session, start_key, end_key, clean_metrics, clean_directories)?;
+            let policy = retention_policy(matches, &config)?;
+
+            let options = scan_options(matches)?;
+
+            metrics_clean(&session, &policy, start_key, end_key, clean_metrics, clean_directories, &options)?;
         },
         Some("local-clean") => {
             let matches = matches.subcommand_matches("local-clean").unwrap();
             let directory = matches.value_of("directory").unwrap();
 
-            metrics_local_clean(&session, directory)?;
+            let policy = retention_policy(matches, &config)?;
+
+            metrics_local_clean(&session, &policy, directory)?;
         }
+        Some("serve") => {
+            let matches = matches.subcommand_matches("serve").unwrap();
+            let address = matches.value_of("listen").unwrap();
+            let workers = matches.value_of("workers").unwrap().parse::<usize>()?;
+
+            metrics_serve(Arc::new(session), address, workers)?;
+        },
+        Some("carbon-listen") => {
+            let matches = matches.subcommand_matches("carbon-listen").unwrap();
+            let address = matches.value_of("listen").unwrap();
+            let queue_size = matches.value_of("queue-size").unwrap().parse::<usize>()?;
+            let batch_size = matches.value_of("batch-size").unwrap().parse::<usize>()?;
+            let cache_size = matches.value_of("cache-size").unwrap().parse::<usize>()?;
+
+            carbon_listen(&session, address, queue_size, batch_size, cache_size)?;
+        },
         None => {
             eprintln!("No command was used.");
             return Ok(());
@@ -306,4 +706,69 @@ fn main() -> Result<(), Box<dyn error::Error>> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches<'static> {
+        let args = ["bgutil-rs"].iter().chain(args.iter()).chain(["info", "app.cpu"].iter());
+
+        app().get_matches_from(args)
+    }
+
+    #[test]
+    fn cluster_options_precedence() {
+        let mut config = Config::parse(r#"
+request_timeout = 10
+protocol_version = 3
+retry_policy = "fallthrough"
+
+[points]
+request_timeout = 20
+protocol_version = 2
+
+[profiles.prod]
+request_timeout = 40
+"#).unwrap();
+        config.set_profile("prod").unwrap();
+
+        // The profile over the cluster table, itself over top-level keys.
+        let options = cluster_options(&matches(&[]), &config, "points").unwrap();
+        assert_eq!(options.request_timeout, 40);
+        assert_eq!(options.protocol_version, 2);
+        assert_eq!(options.retry, Retry::Fallthrough);
+
+        // Options over everything, for both clusters.
+        let matches = matches(&["--request-timeout", "5", "--retry-policy", "default"]);
+        for cluster in &["metadata", "points"] {
+            let options = cluster_options(&matches, &config, cluster).unwrap();
+            assert_eq!(options.request_timeout, 5);
+            assert_eq!(options.retry, Retry::Default);
+        }
+
+        assert_eq!(cluster_options(&matches, &config, "metadata").unwrap().protocol_version, 3);
+    }
+
+    #[test]
+    fn max_components_is_positive() {
+        let parse = |value: &str| app().get_matches_from_safe(vec!["bgutil-rs", "--max-components", value, "info", "app.cpu"]);
+
+        assert_eq!(parse("12").unwrap().value_of("max-components"), Some("12"));
+        assert!(parse("0").is_err());
+        assert!(parse("-1").is_err());
+        assert!(parse("many").is_err());
+    }
 
+    #[test]
+    fn consistency_levels() {
+        let config = Config::parse("read_consistency = \"one\"").unwrap();
+
+        let read = consistency(&matches(&[]), &config, "read-consistency", "read_consistency").unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", Consistency::ONE));
+
+        let write = consistency(&matches(&["--write-consistency", "local_one"]), &config, "write-consistency", "write_consistency").unwrap();
+        assert_eq!(format!("{:?}", write), format!("{:?}", Consistency::LOCAL_ONE));
+
+        assert!(consistency(&matches(&["--read-consistency", "most"]), &config, "read-consistency", "read_consistency").is_err());
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::cell::RefCell;
+use std::collections::{BTreeMap,BTreeSet,HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::error;
+use std::hash::{Hash,Hasher};
+
+use crate::backend::{Backend,NoRecord,Points,ScanResult};
+use crate::glob::glob_match;
+use crate::metric::{default_config,Metric};
+use crate::stage::Stage;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+/// In-memory backend, to run commands in tests.
+pub struct MemoryBackend {
+    metrics: RefCell<BTreeMap<String, Metric>>,
+    directories: RefCell<BTreeSet<String>>,
+    // (metric id, stage table) -> timestamp -> value
+    points: RefCell<HashMap<(String, String), BTreeMap<i64, f64>>>,
+    dry_run: bool,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            metrics: RefCell::new(BTreeMap::new()),
+            directories: RefCell::new(BTreeSet::new()),
+            points: RefCell::new(HashMap::new()),
+            dry_run: false,
+        }
+    }
+
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run
+    }
+
+    /// Create a metric & its directories, last updated at `updated_on` (ms).
+    pub fn add_metric(&self, metric_name: &str, updated_on: u64) {
+        let config = default_config()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>();
+
+        let metric = Metric::new(
+            Uuid::new_v4().to_hyphenated().to_string(),
+            metric_name.to_string(),
+            config,
+            updated_on,
+            updated_on
+        );
+
+        self.metrics.borrow_mut().insert(metric_name.to_string(), metric);
+
+        let parts = metric_name.split(".").collect::<Vec<&str>>();
+        let mut directories = self.directories.borrow_mut();
+        for n in 1..=parts.len() {
+            directories.insert(parts[0..n].join("."));
+        }
+    }
+}
+
+fn token(name: &str) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+
+    hasher.finish() as i64
+}
+
+fn aligned_timestamp(stage: &Stage, timestamp: i64) -> i64 {
+    let (time_start_ms, offset) = stage.time_offset_ms(timestamp);
+
+    time_start_ms / 1000 + offset * stage.precision_as_seconds()
+}
+
+fn is_child(directories: &[String], name: &str) -> bool {
+    let parent = name.rsplit_once('.').map(|x| x.0).unwrap_or("");
+
+    directories.iter().any(|directory| directory == parent)
+}
+
+impl Backend for MemoryBackend {
+    fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn fetch_metric(&self, metric_name: &str) -> Result<Metric, Box<dyn error::Error>> {
+        match self.metrics.borrow().get(metric_name) {
+            Some(metric) => Ok(metric.clone()),
+            None => Err(NoRecord.into()),
+        }
+    }
+
+    fn fetch_metrics(&self, metric_names: &[String]) -> Result<Vec<Metric>, Box<dyn error::Error>> {
+        let metrics = self.metrics.borrow();
+
+        Ok(metric_names.iter().filter_map(|name| metrics.get(name).cloned()).collect())
+    }
+
+    fn create_metric(&self, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
+        self.add_metric(metric_name, Utc::now().timestamp_millis() as u64);
+
+        Ok(())
+    }
+
+    fn delete_metric(&self, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
+        self.metrics.borrow_mut().remove(metric_name);
+
+        Ok(())
+    }
+
+    fn delete_metrics(&self, metric_names: &[String]) -> Vec<Result<(), Box<dyn error::Error>>> {
+        metric_names.iter().map(|name| self.delete_metric(name)).collect()
+    }
+
+    fn delete_directory(&self, directory: &str) -> Result<(), Box<dyn error::Error>> {
+        self.directories.borrow_mut().remove(directory);
+
+        Ok(())
+    }
+
+    fn delete_directories(&self, directories: &[String]) -> Vec<Result<(), Box<dyn error::Error>>> {
+        directories.iter().map(|directory| self.delete_directory(directory)).collect()
+    }
+
+    fn find_metrics(&self, components: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(self.metrics.borrow().keys().filter(|name| glob_match(components, name)).cloned().collect())
+    }
+
+    fn find_directories(&self, components: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(self.directories.borrow().iter().filter(|name| glob_match(components, name)).cloned().collect())
+    }
+
+    fn directory_has_metrics(&self, directory: &str) -> Result<bool, Box<dyn error::Error>> {
+        let prefix = format!("{}.", directory);
+
+        Ok(self.metrics.borrow().keys().any(|name| name.starts_with(&prefix)))
+    }
+
+    fn directories_have_metrics(&self, directories: &[String]) -> Vec<Result<bool, Box<dyn error::Error>>> {
+        directories.iter().map(|directory| self.directory_has_metrics(directory)).collect()
+    }
+
+    fn count_directory_metrics(&self, directory: &str) -> Result<u64, Box<dyn error::Error>> {
+        let prefix = format!("{}.", directory);
+
+        Ok(self.metrics.borrow().keys().filter(|name| name.starts_with(&prefix)).count() as u64)
+    }
+
+    fn find_child_directories(&self, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(self.directories.borrow().iter().filter(|name| is_child(directories, name)).cloned().collect())
+    }
+
+    fn find_child_metrics(&self, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(self.metrics.borrow().keys().filter(|name| is_child(directories, name)).cloned().collect())
+    }
+
+    fn scan_metrics(&self, ranges: &[(i64, i64)], updated_before: Option<u64>, limit: usize) -> ScanResult<Metric> {
+        ranges.iter().map(|(start_token, end_token)| {
+            let mut out = self.metrics.borrow()
+                .values()
+                .map(|metric| (token(metric.name()), metric))
+                .filter(|(t, _)| t > start_token && t < end_token)
+                .filter(|(_, metric)| match updated_before {
+                    Some(cutoff) => metric.updated_on() <= cutoff,
+                    None => true,
+                })
+                .map(|(t, metric)| (t, metric.clone()))
+                .collect::<Vec<(i64, Metric)>>();
+
+            out.sort_by_key(|(t, _)| *t);
+            out.truncate(limit);
+
+            Ok(out)
+        }).collect()
+    }
+
+    fn scan_directories(&self, ranges: &[(i64, i64)], limit: usize) -> ScanResult<String> {
+        ranges.iter().map(|(start_token, end_token)| {
+            let mut out = self.directories.borrow()
+                .iter()
+                .map(|name| (token(name), name.clone()))
+                .filter(|(t, _)| t > start_token && t < end_token)
+                .collect::<Vec<(i64, String)>>();
+
+            out.sort_by_key(|(t, _)| *t);
+            out.truncate(limit);
+
+            Ok(out)
+        }).collect()
+    }
+
+    fn fetch_points(&self, metric: &Metric, stage: &Stage, time_start: i64, time_end: i64)
+        -> Result<Vec<(i64, f64)>, Box<dyn error::Error>> {
+        let key = (metric.id().to_string(), stage.table_name());
+        let points = self.points.borrow();
+
+        let out = match points.get(&key) {
+            None => vec![],
+            Some(values) => {
+                values
+                    .range(aligned_timestamp(stage, time_start)..aligned_timestamp(stage, time_end))
+                    .map(|(ts, value)| (*ts, *value))
+                    .collect()
+            }
+        };
+
+        Ok(out)
+    }
+
+    fn insert_point(&self, metric: &Metric, stage: &Stage, timestamp: i64, value: f64) -> Result<(), Box<dyn error::Error>> {
+        let key = (metric.id().to_string(), stage.table_name());
+
+        self.points.borrow_mut()
+            .entry(key)
+            .or_default()
+            .insert(aligned_timestamp(stage, timestamp), value);
+
+        Ok(())
+    }
+
+    fn fetch_points_many(&self, reads: &[(&Metric, &Stage, i64, i64)]) -> Vec<Result<Points, Box<dyn error::Error>>> {
+        reads
+            .iter()
+            .map(|(metric, stage, time_start, time_end)| self.fetch_points(metric, stage, *time_start, *time_end))
+            .collect()
+    }
+
+    fn insert_points(&self, points: &[(&Metric, &Stage, i64, f64)]) -> Vec<Result<(), Box<dyn error::Error>>> {
+        points
+            .iter()
+            .map(|(metric, stage, timestamp, value)| self.insert_point(metric, stage, *timestamp, *value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-09-13T12:00:00Z
+    const HOUR : i64 = 1599998400;
+
+    fn backend() -> MemoryBackend {
+        let backend = MemoryBackend::new();
+
+        backend.add_metric("app.cpu", 1000);
+        backend.add_metric("app.cpu.user", 2000);
+        backend.add_metric("app.mem", 3000);
+
+        backend
+    }
+
+    #[test]
+    fn create_and_find() {
+        let backend = backend();
+        backend.create_metric("ci.duration").unwrap();
+
+        assert!(backend.fetch_metric("ci.duration").is_ok());
+        assert!(backend.fetch_metric("ci").is_err());
+        assert_eq!(backend.fetch_metrics(&[String::from("app.mem"), String::from("ci")]).unwrap().len(), 1);
+
+        assert_eq!(backend.find_metrics(&["app", "*"]).unwrap(), vec!["app.cpu", "app.mem"]);
+        assert_eq!(backend.find_directories(&["*"]).unwrap(), vec!["app", "ci"]);
+        assert_eq!(backend.find_child_directories(&[String::from("app")]).unwrap(), vec!["app.cpu", "app.mem"]);
+        assert_eq!(backend.find_child_metrics(&[String::from("app.cpu")]).unwrap(), vec!["app.cpu.user"]);
+        assert_eq!(backend.find_child_directories(&[String::new()]).unwrap(), vec!["app", "ci"]);
+    }
+
+    #[test]
+    fn directories_metrics() {
+        let backend = backend();
+
+        assert!(backend.directory_has_metrics("app").unwrap());
+        assert!(backend.directory_has_metrics("app.cpu").unwrap());
+        assert!(!backend.directory_has_metrics("app.mem").unwrap());
+        assert_eq!(backend.count_directory_metrics("app").unwrap(), 3);
+
+        let results = backend.directories_have_metrics(&[String::from("app"), String::from("ci")]);
+        assert_eq!(results.into_iter().map(|x| x.unwrap()).collect::<Vec<bool>>(), vec![true, false]);
+    }
+
+    #[test]
+    fn delete_keeps_directories() {
+        let backend = backend();
+
+        backend.delete_metrics(&[String::from("app.cpu")]).into_iter().for_each(|x| x.unwrap());
+
+        assert!(backend.fetch_metric("app.cpu").is_err());
+        assert_eq!(backend.find_directories(&["app", "cpu"]).unwrap(), vec!["app.cpu"]);
+
+        backend.delete_directory("app.cpu").unwrap();
+        assert!(backend.find_directories(&["app", "cpu"]).unwrap().is_empty());
+        assert!(backend.fetch_metric("app.cpu.user").is_ok());
+    }
+
+    #[test]
+    fn scan_ranges() {
+        let backend = backend();
+
+        let all = backend.scan_metrics(&[(i64::MIN, i64::MAX)], None, 10).pop().unwrap().unwrap();
+        assert_eq!(all.len(), 3);
+        assert!(all.windows(2).all(|x| x[0].0 < x[1].0));
+
+        let limited = backend.scan_metrics(&[(i64::MIN, i64::MAX)], None, 2).pop().unwrap().unwrap();
+        assert_eq!(limited.iter().map(|x| x.0).collect::<Vec<i64>>(), all[..2].iter().map(|x| x.0).collect::<Vec<i64>>());
+
+        // Bounds are exclusive.
+        let after = backend.scan_metrics(&[(all[0].0, i64::MAX)], None, 10).pop().unwrap().unwrap();
+        assert_eq!(after.len(), 2);
+
+        let outdated = backend.scan_metrics(&[(i64::MIN, i64::MAX)], Some(2000), 10).pop().unwrap().unwrap();
+        assert_eq!(outdated.len(), 2);
+
+        assert_eq!(backend.scan_directories(&[(i64::MIN, 0), (0, i64::MAX)], 10).iter().map(|x| x.as_ref().unwrap().len()).sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn points_are_aligned() {
+        let backend = backend();
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stage = metric.stages().unwrap()[1];
+
+        backend.insert_point(&metric, &stage, HOUR + 59, 1.).unwrap();
+        backend.insert_point(&metric, &stage, HOUR + 3600, 2.).unwrap();
+
+        assert_eq!(backend.fetch_points(&metric, &stage, HOUR, HOUR + 7200).unwrap(), vec![(HOUR, 1.), (HOUR + 3600, 2.)]);
+        assert_eq!(backend.fetch_points(&metric, &stage, HOUR, HOUR + 3600).unwrap(), vec![(HOUR, 1.)]);
+    }
+
+    #[test]
+    fn fetch_points_many_keeps_order() {
+        let backend = backend();
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stage = metric.stages().unwrap()[0];
+
+        for minute in 0..200 {
+            backend.insert_point(&metric, &stage, HOUR + minute * 60, minute as f64).unwrap();
+        }
+
+        // Reads in reverse order, each of its own minute.
+        let reads = (0..200).rev().map(|minute| (&metric, &stage, HOUR + minute * 60, HOUR + minute * 60 + 60)).collect::<Vec<_>>();
+        let values = backend.fetch_points_many(&reads)
+            .into_iter()
+            .map(|points| points.unwrap()[0].1)
+            .collect::<Vec<f64>>();
+
+        assert_eq!(values, (0..200).rev().map(|minute| minute as f64).collect::<Vec<f64>>());
+    }
+}
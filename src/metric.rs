@@ -4,15 +4,25 @@
  * Author: Patrick MARIE <pm@mkz.me>
  */
 use crate::Stage;
+use crate::aggregator::Aggregator;
 
 use std::collections::HashMap;
 use std::fmt;
 use std::convert::TryFrom;
 
 use cassandra_cpp::Row;
-use chrono::Utc;
+use chrono::{NaiveDateTime,Utc};
+
+/// Configuration given to newly created metrics
+pub fn default_config() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("aggregator", "average"),
+        ("carbon_xfilesfactor", "0.500000"),
+        ("retention", "11520*60s:720*3600s:730*86400s"),
+    ]
+}
 
-#[derive(Debug)]
+#[derive(Clone,Debug)]
 pub struct Metric {
     id: String,
     name: String,
@@ -22,6 +32,17 @@ pub struct Metric {
 }
 
 impl Metric {
+    #[cfg(test)]
+    pub fn new(id: String, name: String, config: HashMap<String, String>, created_on: u64, updated_on: u64) -> Self {
+        Metric {
+            id: id,
+            name: name,
+            config: config,
+            created_on: created_on,
+            updated_on: updated_on
+        }
+    }
+
     pub fn id(self: &Self) -> &String {
         &self.id
     }
@@ -30,10 +51,21 @@ impl Metric {
         &self.name
     }
 
+    pub fn created_on(self: &Self) -> u64 {
+        self.created_on
+    }
+
     pub fn updated_on(self: &Self) -> u64 {
         self.updated_on
     }
 
+    /// Configuration entries, sorted by key
+    pub fn config_entries(self: &Self) -> Vec<(&String, &String)> {
+        let mut entries = self.config.iter().collect::<Vec<(&String, &String)>>();
+        entries.sort();
+        entries
+    }
+
     pub fn config(self: &Self, name: String) -> Result<String, String> {
         let res = self.config.get(&name);
         if let Some(v) = res {
@@ -62,14 +94,57 @@ impl Metric {
 
         Ok(out)
     }
+
+    /// Finest stage still holding data at time_start, like graphite-web does.
+    pub fn stage_for(self: &Self, time_start: i64, now: i64) -> Result<Stage, String> {
+        let stages = self.stages()?;
+
+        Ok(stages[self.stage_index_for(time_start, now)?])
+    }
+
+    /// Index of `stage_for`'s stage among the metric's stages.
+    pub fn stage_index_for(self: &Self, time_start: i64, now: i64) -> Result<usize, String> {
+        let stages = self.stages()?;
+
+        let index = stages
+            .iter()
+            .position(|s| now - s.retention_as_seconds() <= time_start)
+            .unwrap_or(stages.len() - 1);
+
+        Ok(index)
+    }
+
+    pub fn aggregator(self: &Self) -> Result<Aggregator, String> {
+        let aggregator = self.config("aggregator".to_string())?;
+
+        Aggregator::try_from(aggregator.as_str()).map_err(|err| err.to_string())
+    }
+
+    /// Minimal ratio of known points to aggregate them, 0.5 by default.
+    pub fn xfilesfactor(self: &Self) -> Result<f64, String> {
+        match self.config("carbon_xfilesfactor".to_string()) {
+            Ok(xff) => xff.parse::<f64>().map_err(|err| err.to_string()),
+            Err(_) => Ok(0.5),
+        }
+    }
 }
 
 impl fmt::Display for Metric {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {:?}",
+        let date = |ms: u64| NaiveDateTime::from_timestamp((ms / 1000) as i64, 0).format("%Y-%m-%dT%H:%M:%S");
+
+        write!(f, "{} id={} created_on={} updated_on={}",
             self.name,
-            self.config,
-        )
+            self.id,
+            date(self.created_on),
+            date(self.updated_on),
+        )?;
+
+        for (key, value) in self.config_entries() {
+            write!(f, " {}={}", key, value)?;
+        }
+
+        Ok(())
     }
 }
 
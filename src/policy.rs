@@ -0,0 +1,138 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::error;
+use std::fmt;
+use std::fs;
+
+use crate::glob::glob_match;
+use crate::timeparse::parse_duration;
+
+#[derive(Debug, Clone)]
+pub struct InvalidPolicy(String);
+
+impl fmt::Display for InvalidPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid policy: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidPolicy {}
+
+/// Maximum age of metrics before they are cleaned, by glob pattern.
+///
+/// A policy file holds a `<glob> <max age>` rule per line, like `ci.** 3d`;
+/// `#` starts a comment. The first matching rule applies, metrics matching
+/// none get the default max age.
+pub struct RetentionPolicy {
+    rules: Vec<(String, i64)>,
+    default_max_age: i64,
+}
+
+impl RetentionPolicy {
+    pub fn new(default_max_age: i64) -> Self {
+        RetentionPolicy {
+            rules: vec![],
+            default_max_age: default_max_age,
+        }
+    }
+
+    pub fn parse(content: &str, default_max_age: i64) -> Result<Self, InvalidPolicy> {
+        let mut policy = RetentionPolicy::new(default_max_age);
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || InvalidPolicy(format!("line {}: {}", number + 1, line));
+
+            let parts = line.split_whitespace().collect::<Vec<&str>>();
+            if parts.len() != 2 {
+                return Err(invalid());
+            }
+
+            let max_age = parse_duration(parts[1]).map_err(|_| invalid())?;
+            policy.rules.push((parts[0].to_string(), max_age));
+        }
+
+        Ok(policy)
+    }
+
+    pub fn load(path: &str, default_max_age: i64) -> Result<Self, Box<dyn error::Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| InvalidPolicy(format!("{}: {}", path, err)))?;
+
+        Ok(RetentionPolicy::parse(&content, default_max_age)?)
+    }
+
+    /// Maximum age of a metric, in seconds
+    pub fn max_age(self: &Self, name: &str) -> i64 {
+        for (pattern, max_age) in self.rules.iter() {
+            if glob_match(&pattern.split(".").collect::<Vec<&str>>(), name) {
+                return *max_age;
+            }
+        }
+
+        self.default_max_age
+    }
+
+    /// `updated_on` (ms) before which a metric is outdated
+    pub fn cutoff(self: &Self, name: &str, now: i64) -> u64 {
+        ((now - self.max_age(name)) * 1000) as u64
+    }
+
+    /// Latest cutoff of all rules: no metric updated after it is outdated.
+    pub fn max_cutoff(self: &Self, now: i64) -> u64 {
+        let min_max_age = self.rules
+            .iter()
+            .map(|(_, max_age)| *max_age)
+            .fold(self.default_max_age, std::cmp::min);
+
+        ((now - min_max_age) * 1000) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY : i64 = 86400;
+    const NOW : i64 = 1600000000;
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let policy = RetentionPolicy::parse("# ci is short-lived\nci.build.** 1d\nci.** 3d # others\n\n", 30 * DAY).unwrap();
+
+        assert_eq!(policy.max_age("ci.build.duration"), DAY);
+        assert_eq!(policy.max_age("ci.test.duration"), 3 * DAY);
+        assert_eq!(policy.max_age("app.cpu"), 30 * DAY);
+        assert_eq!(policy.cutoff("ci.test.duration", NOW), ((NOW - 3 * DAY) * 1000) as u64);
+
+        // Later rules do not override earlier ones.
+        let policy = RetentionPolicy::parse("ci.** 3d\nci.build.** 1d", 30 * DAY).unwrap();
+        assert_eq!(policy.max_age("ci.build.duration"), 3 * DAY);
+    }
+
+    #[test]
+    fn max_cutoff_is_the_shortest_max_age() {
+        assert_eq!(RetentionPolicy::new(30 * DAY).max_cutoff(NOW), ((NOW - 30 * DAY) * 1000) as u64);
+
+        let policy = RetentionPolicy::parse("ci.** 3d\narchive.** 365d", 30 * DAY).unwrap();
+        assert_eq!(policy.max_cutoff(NOW), ((NOW - 3 * DAY) * 1000) as u64);
+
+        // Rules longer than the default do not move it.
+        let policy = RetentionPolicy::parse("archive.** 365d", 30 * DAY).unwrap();
+        assert_eq!(policy.max_cutoff(NOW), ((NOW - 30 * DAY) * 1000) as u64);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(RetentionPolicy::parse("ci.**", DAY).is_err());
+        assert!(RetentionPolicy::parse("ci.** 3d extra", DAY).is_err());
+        assert!(RetentionPolicy::parse("ci.** 3", DAY).is_err());
+    }
+}
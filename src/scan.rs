@@ -0,0 +1,475 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use crate::backend::ScanResult;
+use crate::config::Config;
+
+const PROGRESS_INTERVAL : Duration = Duration::from_secs(10);
+const CHECKPOINT_INTERVAL : Duration = Duration::from_secs(30);
+
+static INTERRUPTED : AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone)]
+pub struct InvalidState(String);
+
+impl fmt::Display for InvalidState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid scan state: {}", self.0)
+    }
+}
+
+impl error::Error for InvalidState {}
+
+#[derive(Debug, Clone)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl error::Error for Interrupted {}
+
+/// Settings of token range scans
+#[derive(Clone,Debug)]
+pub struct ScanOptions {
+    /// Number of sub-ranges scanned concurrently
+    pub workers: usize,
+    /// Maximum number of queries per second
+    pub rate: Option<u64>,
+    /// File the scan state is saved to
+    pub state_file: Option<String>,
+    /// Resume the scan saved in `state_file`
+    pub resume: bool,
+}
+
+/// Position of a worker in its (start, end) token range.
+#[derive(Clone,Debug)]
+pub struct Cursor {
+    pub start: i64,
+    pub position: i64,
+    pub end: i64,
+    pub done: bool,
+}
+
+/// Counters of a scan, by name.
+pub type Counters = BTreeMap<String, u64>;
+
+/// Progress of a scan, saved to resume it later.
+///
+/// `phase` is up to the command: for example, clean scans metrics then
+/// directories.
+#[derive(Clone,Debug)]
+pub struct ScanState {
+    pub command: String,
+    pub phase: String,
+    pub cursors: Vec<Cursor>,
+    pub counters: Counters,
+}
+
+impl ScanState {
+    pub fn new(command: &str, phase: &str, cursors: Vec<Cursor>) -> Self {
+        ScanState {
+            command: command.to_string(),
+            phase: phase.to_string(),
+            cursors: cursors,
+            counters: Counters::new(),
+        }
+    }
+
+    pub fn counter(self: &Self, name: &str) -> u64 {
+        *self.counters.get(name).unwrap_or(&0)
+    }
+
+    /// Load a state saved by `command`.
+    pub fn load(path: &str, command: &str) -> Result<Self, Box<dyn error::Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| InvalidState(format!("{}: {}", path, err)))?;
+        let state = Config::parse(&content)?;
+
+        let get = |key: &str| {
+            state.get(key).ok_or_else(|| InvalidState(format!("{}: missing {}", path, key)))
+        };
+
+        if get("command")? != command {
+            return Err(InvalidState(format!("{} is a state of {}, not {}", path, get("command")?, command)).into());
+        }
+
+        let mut counters = Counters::new();
+        for (name, value) in state.table("counters") {
+            counters.insert(name.to_string(), value.parse::<u64>()?);
+        }
+
+        let mut cursors = vec![];
+        while state.get(&format!("cursors.{}.start", cursors.len())).is_some() {
+            let get_cursor = |key: &str| get(&format!("cursors.{}.{}", cursors.len(), key));
+
+            cursors.push(Cursor {
+                start: get_cursor("start")?.parse::<i64>()?,
+                position: get_cursor("position")?.parse::<i64>()?,
+                end: get_cursor("end")?.parse::<i64>()?,
+                done: get_cursor("done")?.parse::<bool>()?,
+            });
+        }
+
+        Ok(ScanState {
+            command: command.to_string(),
+            phase: get("phase")?.to_string(),
+            cursors: cursors,
+            counters: counters,
+        })
+    }
+
+    pub fn save(self: &Self, path: &str) -> Result<(), Box<dyn error::Error>> {
+        let mut content = format!("command = {}\nphase = {}\n\n[counters]\n", quote(&self.command), quote(&self.phase));
+
+        // Counters are named after metrics' components: keys are quoted.
+        for (name, value) in self.counters.iter() {
+            content.push_str(&format!("{} = {}\n", quote(name), value));
+        }
+
+        for (id, cursor) in self.cursors.iter().enumerate() {
+            content.push_str(&format!("\n[cursors.{}]\nstart = {}\nposition = {}\nend = {}\ndone = {}\n",
+                id, cursor.start, cursor.position, cursor.end, cursor.done));
+        }
+
+        // Do not leave a truncated state behind if interrupted while writing.
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+/// Quote a string for the state file.
+fn quote(value: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+
+    // A second Ctrl-C stops right away.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Make Ctrl-C stop scans after the current row, so their state is saved.
+/// Returns the previous handler, to restore it.
+fn catch_interrupt() -> libc::sighandler_t {
+    unsafe {
+        libc::signal(libc::SIGINT, interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t)
+    }
+}
+
+fn restore_interrupt(handler: libc::sighandler_t) {
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Split the (start, end) token range in `workers` contiguous cursors, at
+/// most one per token.
+pub fn split_range(start: i64, end: i64, workers: usize) -> Vec<Cursor> {
+    let width = std::cmp::max(end as i128 - start as i128, 1);
+    let workers = std::cmp::min(std::cmp::max(workers, 1) as i128, width);
+    let size = (end as i128 - start as i128) / workers;
+
+    (0..workers)
+        .map(|id| {
+            let position = (start as i128 + id * size) as i64;
+            // Bounds are exclusive: a range ends right after the next one starts.
+            let end = if id == workers - 1 {
+                end
+            } else {
+                (start as i128 + (id + 1) * size + 1) as i64
+            };
+
+            Cursor {
+                start: position,
+                position: position,
+                end: end,
+                done: false,
+            }
+        })
+        .collect()
+}
+
+/// Scanned part of all cursors' ranges, between 0 and 1.
+pub fn progress(cursors: &[Cursor]) -> f64 {
+    let total : f64 = cursors.iter().map(|c| c.end as f64 - c.start as f64).sum();
+    let scanned : f64 = cursors
+        .iter()
+        .map(|c| if c.done { c.end as f64 - c.start as f64 } else { c.position as f64 - c.start as f64 })
+        .sum();
+
+    if total <= 0. {
+        return 1.;
+    }
+
+    scanned / total
+}
+
+/// Maximum number of queries per second, shared by all workers.
+pub struct RateLimiter {
+    rate: Option<u64>,
+    start: Instant,
+    count: Cell<u64>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        RateLimiter {
+            rate: rate,
+            start: Instant::now(),
+            count: Cell::new(0),
+        }
+    }
+
+    /// Wait until `n` more queries are allowed.
+    pub fn acquire(self: &Self, n: u64) {
+        let rate = match self.rate {
+            Some(rate) if rate != 0 => rate,
+            _ => return,
+        };
+
+        self.count.set(self.count.get() + n);
+
+        let expected = Duration::from_secs_f64(self.count.get() as f64 / rate as f64);
+        let elapsed = self.start.elapsed();
+
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+/// Scan the state cursors' ranges by pages of `limit` rows. Each round
+/// fetches a page for every unfinished cursor at once, then hands all their
+/// rows to `process`, so it can work on them at once.
+///
+/// With a state file, the state is saved there periodically and when the
+/// scan ends, even on error or Ctrl-C.
+pub fn scan<T, F, P>(state: &mut ScanState, options: &ScanOptions, limiter: &RateLimiter, limit: usize, fetch: F, process: P)
+    -> Result<(), Box<dyn error::Error>>
+    where F: Fn(&[(i64, i64)]) -> ScanResult<T>,
+          P: FnMut(&[&T], &mut Counters) -> Result<(), Box<dyn error::Error>> {
+    let path = match &options.state_file {
+        Some(path) => path,
+        None => return scan_cursors(state, None, limiter, limit, fetch, process),
+    };
+
+    let handler = catch_interrupt();
+
+    let result = scan_cursors(state, Some(path), limiter, limit, fetch, process);
+    restore_interrupt(handler);
+    state.save(path)?;
+
+    if result.is_err() {
+        eprintln!("Scan state saved to {}, continue with --resume {}", path, path);
+    }
+
+    result
+}
+
+fn scan_cursors<T, F, P>(state: &mut ScanState, path: Option<&str>, limiter: &RateLimiter, limit: usize, fetch: F, mut process: P)
+    -> Result<(), Box<dyn error::Error>>
+    where F: Fn(&[(i64, i64)]) -> ScanResult<T>,
+          P: FnMut(&[&T], &mut Counters) -> Result<(), Box<dyn error::Error>> {
+    let mut last_report = Instant::now();
+    let mut last_checkpoint = Instant::now();
+
+    loop {
+        let active = state.cursors
+            .iter()
+            .enumerate()
+            .filter(|(_, cursor)| !cursor.done && cursor.position < cursor.end)
+            .map(|(id, _)| id)
+            .collect::<Vec<usize>>();
+
+        if active.is_empty() {
+            break;
+        }
+
+        let ranges = active
+            .iter()
+            .map(|id| (state.cursors[*id].position, state.cursors[*id].end))
+            .collect::<Vec<(i64, i64)>>();
+
+        limiter.acquire(ranges.len() as u64);
+
+        let mut pages = vec![];
+        for rows in fetch(&ranges) {
+            pages.push(rows?);
+        }
+
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Err(Interrupted.into());
+        }
+
+        // Counters must not include a round that failed: it is processed
+        // again when resuming.
+        let counters = state.counters.clone();
+        let rows = pages.iter().flat_map(|rows| rows.iter().map(|(_, row)| row)).collect::<Vec<&T>>();
+        if let Err(err) = process(&rows, &mut state.counters) {
+            state.counters = counters;
+            return Err(err);
+        }
+
+        for (id, rows) in active.iter().zip(pages.iter()) {
+            if let Some((token, _)) = rows.last() {
+                state.cursors[*id].position = *token;
+            }
+
+            if rows.len() < limit {
+                state.cursors[*id].done = true;
+            }
+        }
+
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            eprintln!("Scanned {:.2}% of token range", 100. * progress(&state.cursors));
+            last_report = Instant::now();
+        }
+
+        if let Some(path) = path {
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                state.save(path)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Backend;
+    use crate::Metric;
+    use crate::memory::MemoryBackend;
+
+    use std::env;
+
+    fn state_file(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("bgutil-rs-{}-{}.state", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn bounds(cursors: &[Cursor]) -> Vec<(i64, i64)> {
+        cursors.iter().map(|cursor| (cursor.start, cursor.end)).collect()
+    }
+
+    #[test]
+    fn split_range_in_contiguous_cursors() {
+        assert_eq!(bounds(&split_range(0, 100, 4)), vec![(0, 26), (25, 51), (50, 76), (75, 100)]);
+        assert_eq!(bounds(&split_range(0, 100, 0)), vec![(0, 100)]);
+
+        let cursors = split_range(i64::MIN, i64::MAX, 3);
+        assert_eq!(cursors.first().unwrap().start, i64::MIN);
+        assert_eq!(cursors.last().unwrap().end, i64::MAX);
+    }
+
+    #[test]
+    fn split_range_clamps_workers_to_width() {
+        assert_eq!(bounds(&split_range(0, 2, 8)), vec![(0, 2), (1, 2)]);
+        assert_eq!(bounds(&split_range(5, 5, 8)), vec![(5, 5)]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = state_file("save");
+        let mut state = ScanState::new("stats", "metrics", split_range(-100, 100, 2));
+        state.cursors[0].position = -10;
+        state.cursors[1].done = true;
+
+        for name in &["metrics", "roots.a=b", "roots.with space", "roots.[x]", "roots.\"quoted\\", "roots.tab\t#"] {
+            state.counters.insert(name.to_string(), name.len() as u64);
+        }
+
+        state.save(&path).unwrap();
+        let loaded = ScanState::load(&path, "stats").unwrap();
+        assert!(ScanState::load(&path, "clean").is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.phase, "metrics");
+        assert_eq!(loaded.counters, state.counters);
+        assert_eq!(bounds(&loaded.cursors), bounds(&state.cursors));
+        assert_eq!(loaded.cursors[0].position, -10);
+        assert!(loaded.cursors[1].done);
+    }
+
+    #[test]
+    fn resume_interrupted_scan() {
+        let path = state_file("resume");
+        let backend = MemoryBackend::new();
+        for id in 0..10 {
+            backend.add_metric(&format!("app.{}", id), 0);
+        }
+
+        let options = ScanOptions {
+            workers: 2,
+            rate: None,
+            state_file: Some(path.clone()),
+            resume: false,
+        };
+        let limiter = RateLimiter::new(None);
+        let count = |metrics: &[&Metric], counters: &mut Counters| {
+            *counters.entry(String::from("metrics")).or_insert(0) += metrics.len() as u64;
+            Ok(())
+        };
+
+        // Fail on the third round: its rows are counted again on resume.
+        let mut state = ScanState::new("stats", "metrics", split_range(i64::MIN, i64::MAX, options.workers));
+        let mut rounds = 0;
+        let result = scan(&mut state, &options, &limiter, 1,
+            |ranges| backend.scan_metrics(ranges, None, 1),
+            |metrics: &[&Metric], counters: &mut Counters| {
+                rounds += 1;
+                if rounds == 3 {
+                    return Err(InvalidState(String::from("failure")).into());
+                }
+                count(metrics, counters)
+            });
+        assert!(result.is_err());
+
+        let mut state = ScanState::load(&path, "stats").unwrap();
+        assert!(state.counter("metrics") > 0 && state.counter("metrics") < 10);
+
+        scan(&mut state, &options, &limiter, 1, |ranges| backend.scan_metrics(ranges, None, 1), count).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(state.counter("metrics"), 10);
+        assert!(state.cursors.iter().all(|cursor| cursor.done));
+    }
+}
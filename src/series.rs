@@ -0,0 +1,245 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::collections::HashMap;
+use std::error;
+
+use crate::backend::Backend;
+use crate::metric::Metric;
+use crate::stage::Stage;
+
+/// Points read from a single stage over [time_start, time_end).
+pub struct Segment {
+    pub stage: Stage,
+    pub time_start: i64,
+    pub time_end: i64,
+    pub points: Vec<(i64, f64)>,
+}
+
+impl Segment {
+    pub fn step(self: &Self) -> i64 {
+        self.stage.precision_as_seconds()
+    }
+
+    /// Values of every step boundary within [time_start, time_end), None if missing.
+    pub fn slots(self: &Self) -> Vec<(i64, Option<f64>)> {
+        let step = self.step();
+        let start = self.time_start + (step - self.time_start.rem_euclid(step)) % step;
+
+        let points = self.points.iter().cloned().collect::<HashMap<i64, f64>>();
+
+        (start..self.time_end)
+            .step_by(step as usize)
+            .map(|ts| (ts, points.get(&ts).cloned()))
+            .collect()
+    }
+}
+
+/// A metric's points, as chronological segments.
+pub struct Series {
+    pub name: String,
+    pub segments: Vec<Segment>,
+}
+
+impl Series {
+    pub fn points(self: &Self) -> Vec<(i64, f64)> {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.points.iter().cloned())
+            .collect()
+    }
+
+    /// Points, or every segment's step-aligned slots with None for missing points.
+    pub fn values(self: &Self, fill_nulls: bool) -> Vec<(i64, Option<f64>)> {
+        if fill_nulls {
+            self.segments
+                .iter()
+                .flat_map(|segment| segment.slots())
+                .collect()
+        } else {
+            self.points()
+                .iter()
+                .map(|(ts, value)| (*ts, Some(*value)))
+                .collect()
+        }
+    }
+}
+
+/// Read points of a single stage.
+pub fn fetch_stage_series(backend: &dyn Backend, metric: &Metric, stage: &Stage, time_start: i64, time_end: i64)
+    -> Result<Series, Box<dyn error::Error>> {
+    let points = backend.fetch_points(metric, stage, time_start, time_end)?;
+
+    Ok(Series {
+        name: metric.name().to_string(),
+        segments: vec![Segment {
+            stage: *stage,
+            time_start: time_start,
+            time_end: time_end,
+            points: points,
+        }],
+    })
+}
+
+/// Read points across stages: each period comes from the finest stage
+/// still retaining it, older periods from coarser stages. Segments are
+/// read at once.
+pub fn fetch_series(backend: &dyn Backend, metric: &Metric, time_start: i64, time_end: i64, now: i64)
+    -> Result<Series, Box<dyn error::Error>> {
+    let stages = metric.stages()?;
+    let mut reads = vec![];
+    let mut end = time_end;
+
+    for (id, stage) in stages.iter().enumerate() {
+        if end <= time_start {
+            break;
+        }
+
+        // Start where the stage's retention begins, aligned on the next
+        // stage's step so the coarser segment ends on a whole bucket.
+        let mut start = time_start;
+        if let Some(next) = stages.get(id + 1) {
+            let retention_start = now - stage.retention_as_seconds();
+
+            if retention_start > time_start {
+                let next_step = next.precision_as_seconds();
+                start = retention_start + (next_step - retention_start.rem_euclid(next_step)) % next_step;
+            }
+        }
+
+        if start >= end {
+            continue;
+        }
+
+        reads.push((metric, stage, start, end));
+
+        end = start;
+    }
+
+    reads.reverse();
+
+    let mut segments = vec![];
+    for ((_, stage, start, end), points) in reads.iter().zip(backend.fetch_points_many(&reads)) {
+        segments.push(Segment {
+            stage: **stage,
+            time_start: *start,
+            time_end: *end,
+            points: points?,
+        });
+    }
+
+    Ok(Series {
+        name: metric.name().to_string(),
+        segments: segments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    use std::convert::TryFrom;
+
+    // 2020-09-13T12:26:40Z
+    const NOW : i64 = 1600000000;
+    const DAY : i64 = 86400;
+
+    fn bounds(series: &Series) -> Vec<(String, i64, i64)> {
+        series.segments
+            .iter()
+            .map(|segment| (segment.stage.to_string(), segment.time_start, segment.time_end))
+            .collect()
+    }
+
+    fn segment(time_start: i64, time_end: i64, points: Vec<(i64, f64)>) -> Segment {
+        Segment {
+            stage: Stage::try_from("11520*60s").unwrap(),
+            time_start: time_start,
+            time_end: time_end,
+            points: points,
+        }
+    }
+
+    #[test]
+    fn slots_are_aligned_on_step() {
+        let hour = NOW - NOW.rem_euclid(3600);
+
+        // Only step boundaries within the bounds.
+        let slots = segment(hour + 30, hour + 150, vec![(hour + 60, 1.)]).slots();
+        assert_eq!(slots, vec![(hour + 60, Some(1.)), (hour + 120, None)]);
+
+        let slots = segment(hour, hour + 120, vec![(hour, 1.)]).slots();
+        assert_eq!(slots, vec![(hour, Some(1.)), (hour + 60, None)]);
+
+        assert!(segment(hour + 10, hour + 50, vec![]).slots().is_empty());
+    }
+
+    #[test]
+    fn values_fill_nulls() {
+        let hour = NOW - NOW.rem_euclid(3600);
+        let series = Series {
+            name: String::from("app.cpu"),
+            segments: vec![
+                segment(hour, hour + 120, vec![(hour + 60, 1.)]),
+                segment(hour + 120, hour + 240, vec![(hour + 120, 2.)]),
+            ],
+        };
+
+        assert_eq!(series.values(false), vec![(hour + 60, Some(1.)), (hour + 120, Some(2.))]);
+        assert_eq!(series.values(true), vec![
+            (hour, None), (hour + 60, Some(1.)), (hour + 120, Some(2.)), (hour + 180, None),
+        ]);
+    }
+
+    #[test]
+    fn fetch_series_within_finest_stage() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+
+        let series = fetch_series(&backend, &metric, NOW - DAY, NOW, NOW).unwrap();
+        assert_eq!(bounds(&series), vec![(String::from("11520*60s"), NOW - DAY, NOW)]);
+    }
+
+    #[test]
+    fn fetch_series_stitches_on_coarser_steps() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+
+        // Minutes are kept 8 days, hours 30 days: boundaries are rounded up
+        // to the coarser stage's step.
+        let minutes_start = NOW - 8 * DAY + 3600 - (NOW - 8 * DAY).rem_euclid(3600);
+        let hours_start = NOW - 30 * DAY + DAY - (NOW - 30 * DAY).rem_euclid(DAY);
+
+        let series = fetch_series(&backend, &metric, NOW - 40 * DAY, NOW, NOW).unwrap();
+        assert_eq!(bounds(&series), vec![
+            (String::from("730*86400s"), NOW - 40 * DAY, hours_start),
+            (String::from("720*3600s"), hours_start, minutes_start),
+            (String::from("11520*60s"), minutes_start, NOW),
+        ]);
+        assert_eq!(minutes_start % 3600, 0);
+        assert_eq!(hours_start % DAY, 0);
+    }
+
+    #[test]
+    fn fetch_series_reads_each_stage() {
+        let backend = MemoryBackend::new();
+        backend.add_metric("app.cpu", 0);
+        let metric = backend.fetch_metric("app.cpu").unwrap();
+        let stages = metric.stages().unwrap();
+
+        let hour = NOW - NOW.rem_euclid(3600);
+        backend.insert_point(&metric, &stages[1], hour - 10 * DAY, 1.).unwrap();
+        // Yesterday is in both stages: the finest one wins.
+        backend.insert_point(&metric, &stages[1], hour - DAY, 2.).unwrap();
+        backend.insert_point(&metric, &stages[0], hour - DAY, 3.).unwrap();
+
+        let series = fetch_series(&backend, &metric, NOW - 20 * DAY, NOW, NOW).unwrap();
+        assert_eq!(series.segments.len(), 2);
+        assert_eq!(series.points(), vec![(hour - 10 * DAY, 1.), (hour - DAY, 3.)]);
+    }
+}
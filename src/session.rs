@@ -3,28 +3,48 @@
  *
  * Author: Patrick MARIE <pm@mkz.me>
  */
+use std::error;
+use std::sync::atomic::{AtomicUsize,Ordering};
 
 use cassandra_cpp::Session as CassSession;
-use cassandra_cpp::Error as CassError;
 use cassandra_cpp::Consistency;
 
+use crate::backend::{Backend,Points,ScanResult};
 use crate::cassandra::*;
+use crate::metric::Metric;
+use crate::stage::Stage;
 
 pub struct Session {
     metadata: CassSession,
     points: CassSession,
     dry_run: bool,
+    read_parallelism: usize,
+    // 0 until known
+    max_components: AtomicUsize,
+    find_strategy: FindStrategy,
+    metadata_keyspace: String,
+    points_keyspace: String,
+    read_consistency: Consistency,
+    write_consistency: Consistency,
 }
 
 impl Session {
-    pub fn new(metadata_contact: &str, points_contact: &str) -> Result<Self, CassError> {
-        let metadata = connect(metadata_contact)?;
-        let points = connect(points_contact)?;
+    pub fn new(metadata_contact: &str, points_contact: &str, metadata_options: &ClusterOptions, points_options: &ClusterOptions)
+        -> Result<Self, Box<dyn error::Error>> {
+        let metadata = connect(metadata_contact, metadata_options)?;
+        let points = connect(points_contact, points_options)?;
 
         let session = Self {
             metadata: metadata,
             points: points,
             dry_run: false,
+            read_parallelism: 16,
+            max_components: AtomicUsize::new(0),
+            find_strategy: FindStrategy::Auto,
+            metadata_keyspace: String::from("biggraphite_metadata"),
+            points_keyspace: String::from("biggraphite"),
+            read_consistency: Consistency::LOCAL_QUORUM,
+            write_consistency: Consistency::LOCAL_QUORUM,
         };
 
         Ok(session)
@@ -34,6 +54,54 @@ impl Session {
         self.dry_run = dry_run
     }
 
+    pub fn set_read_parallelism(&mut self, read_parallelism: usize) {
+        self.read_parallelism = read_parallelism
+    }
+
+    /// Maximum number of concurrent partition queries per read
+    pub fn read_parallelism(&self) -> usize {
+        self.read_parallelism
+    }
+
+    pub fn set_max_components(&mut self, max_components: usize) {
+        self.max_components.store(max_components, Ordering::Relaxed)
+    }
+
+    /// Maximum number of components in a name, discovered from the schema
+    /// on first use unless set.
+    pub fn max_components(&self) -> Result<usize, Box<dyn error::Error>> {
+        let max_components = self.max_components.load(Ordering::Relaxed);
+        if max_components != 0 {
+            return Ok(max_components);
+        }
+
+        let max_components = fetch_max_components(self)?;
+        self.max_components.store(max_components, Ordering::Relaxed);
+
+        Ok(max_components)
+    }
+
+    pub fn set_find_strategy(&mut self, find_strategy: FindStrategy) {
+        self.find_strategy = find_strategy
+    }
+
+    pub fn find_strategy(&self) -> FindStrategy {
+        self.find_strategy
+    }
+
+    pub fn set_keyspaces(&mut self, metadata_keyspace: &str, points_keyspace: &str) {
+        self.metadata_keyspace = metadata_keyspace.to_string();
+        self.points_keyspace = points_keyspace.to_string();
+    }
+
+    pub fn metadata_keyspace(&self) -> &str {
+        &self.metadata_keyspace
+    }
+
+    pub fn points_keyspace(&self) -> &str {
+        &self.points_keyspace
+    }
+
     pub fn metadata_session(&self) -> &CassSession {
         &self.metadata
     }
@@ -42,17 +110,124 @@ impl Session {
         &self.points
     }
 
-    // XXX to make configurable
+    pub fn set_consistency(&mut self, read_consistency: Consistency, write_consistency: Consistency) {
+        self.read_consistency = read_consistency;
+        self.write_consistency = write_consistency;
+    }
+
     pub fn read_consistency(&self) -> Consistency {
-        Consistency::LOCAL_QUORUM
+        self.read_consistency
     }
 
-    // XXX to make configurable
     pub fn write_consistency(&self) -> Consistency {
-        Consistency::LOCAL_QUORUM
+        self.write_consistency
     }
+}
 
-    pub fn is_dry_run(&self) -> bool {
+impl Backend for Session {
+    fn is_dry_run(&self) -> bool {
         self.dry_run
     }
+
+    fn fetch_metric(&self, metric_name: &str) -> Result<Metric, Box<dyn error::Error>> {
+        fetch_metric(self, metric_name)
+    }
+
+    fn fetch_metrics(&self, metric_names: &[String]) -> Result<Vec<Metric>, Box<dyn error::Error>> {
+        Ok(fetch_metrics(self, metric_names)?)
+    }
+
+    fn create_metric(&self, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
+        Ok(create_metric(self, metric_name)?)
+    }
+
+    fn delete_metric(&self, metric_name: &str) -> Result<(), Box<dyn error::Error>> {
+        Ok(delete_metric(self, metric_name)?)
+    }
+
+    fn delete_metrics(&self, metric_names: &[String]) -> Vec<Result<(), Box<dyn error::Error>>> {
+        delete_metrics(self, metric_names)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
+
+    fn delete_directory(&self, directory: &str) -> Result<(), Box<dyn error::Error>> {
+        Ok(delete_directory(self, directory)?)
+    }
+
+    fn delete_directories(&self, directories: &[String]) -> Vec<Result<(), Box<dyn error::Error>>> {
+        delete_directories(self, directories)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
+
+    fn find_metrics(&self, components: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        fetch_component_names(self, "metrics", components)
+    }
+
+    fn find_directories(&self, components: &[&str]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        fetch_component_names(self, "directories", components)
+    }
+
+    fn directory_has_metrics(&self, directory: &str) -> Result<bool, Box<dyn error::Error>> {
+        Ok(directory_has_metrics(self, directory)?)
+    }
+
+    fn directories_have_metrics(&self, directories: &[String]) -> Vec<Result<bool, Box<dyn error::Error>>> {
+        directories_have_metrics(self, directories)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
+
+    fn count_directory_metrics(&self, directory: &str) -> Result<u64, Box<dyn error::Error>> {
+        Ok(count_directory_metrics(self, directory)?)
+    }
+
+    fn find_child_directories(&self, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(fetch_children(self, "directories", directories)?)
+    }
+
+    fn find_child_metrics(&self, directories: &[String]) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(fetch_children(self, "metrics", directories)?)
+    }
+
+    fn scan_metrics(&self, ranges: &[(i64, i64)], updated_before: Option<u64>, limit: usize) -> ScanResult<Metric> {
+        scan_metrics(self, ranges, updated_before, limit)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
+
+    fn scan_directories(&self, ranges: &[(i64, i64)], limit: usize) -> ScanResult<String> {
+        scan_directories(self, ranges, limit)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
+
+    fn fetch_points(&self, metric: &Metric, stage: &Stage, time_start: i64, time_end: i64)
+        -> Result<Vec<(i64, f64)>, Box<dyn error::Error>> {
+        Ok(fetch_points(self, metric, stage, time_start, time_end)?)
+    }
+
+    fn fetch_points_many(&self, reads: &[(&Metric, &Stage, i64, i64)]) -> Vec<Result<Points, Box<dyn error::Error>>> {
+        fetch_points_many(self, reads)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
+
+    fn insert_point(&self, metric: &Metric, stage: &Stage, timestamp: i64, value: f64) -> Result<(), Box<dyn error::Error>> {
+        Ok(insert_point(self, metric, stage, timestamp, value)?)
+    }
+
+    fn insert_points(&self, points: &[(&Metric, &Stage, i64, f64)]) -> Vec<Result<(), Box<dyn error::Error>>> {
+        insert_points(self, points)
+            .into_iter()
+            .map(|result| result.map_err(|err| err.into()))
+            .collect()
+    }
 }
@@ -64,6 +64,10 @@ impl Stage {
         self.points
     }
 
+    pub fn retention_as_seconds(self: &Self) -> i64 {
+        self.precision_as_seconds() * self.points as i64
+    }
+
     pub fn to_string(self: &Self) -> String {
         format!("{}*{}{}", self.points, self.precision, self.factor)
     }
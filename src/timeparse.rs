@@ -0,0 +1,187 @@
+/*
+ * bgutil-rs
+ *
+ * Author: Patrick MARIE <pm@mkz.me>
+ */
+use std::error;
+use std::fmt;
+
+use chrono::{DateTime,NaiveDate,NaiveDateTime};
+
+#[derive(Debug, Clone)]
+pub struct InvalidTime(String);
+
+impl fmt::Display for InvalidTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid time '{}'", self.0)
+    }
+}
+
+impl error::Error for InvalidTime {}
+
+/// Unit of a graphite offset, matched by prefix like graphite does.
+fn unit_as_seconds(unit: &str) -> Option<i64> {
+    if unit.starts_with("s") {
+        Some(1)
+    } else if unit.starts_with("min") {
+        Some(60)
+    } else if unit.starts_with("h") {
+        Some(3600)
+    } else if unit.starts_with("d") {
+        Some(86400)
+    } else if unit.starts_with("w") {
+        Some(7 * 86400)
+    } else if unit.starts_with("mon") {
+        Some(30 * 86400)
+    } else if unit.starts_with("y") {
+        Some(365 * 86400)
+    } else {
+        None
+    }
+}
+
+/// Parse an `<n><unit>` offset, in seconds.
+fn parse_offset(offset: &str) -> Option<i64> {
+    let pos = offset.find(|c: char| !c.is_ascii_digit())?;
+    let (n, unit) = offset.split_at(pos);
+
+    // Overflowing offsets are invalid, not wrapped around.
+    n.parse::<i64>().ok()?.checked_mul(unit_as_seconds(unit)?)
+}
+
+/// Parse a duration like `30d`, `12h` or `2weeks`, in seconds.
+pub fn parse_duration(value: &str) -> Result<i64, InvalidTime> {
+    parse_offset(value.trim()).ok_or_else(|| InvalidTime(value.to_string()))
+}
+
+fn parse_date(value: &str) -> Option<i64> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.timestamp());
+    }
+
+    for format in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M", "%H:%M_%Y%m%d"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(date.timestamp());
+        }
+    }
+
+    for format in &["%Y-%m-%d", "%Y%m%d", "%m/%d/%y"] {
+        if let Ok(date) = NaiveDate::parse_from_str(value, format) {
+            return Some(date.and_hms(0, 0, 0).timestamp());
+        }
+    }
+
+    None
+}
+
+/// Parse a time expression into a unix timestamp. Dates are UTC.
+///
+/// Accepts unix timestamps, RFC 3339 / ISO 8601 dates, graphite's absolute
+/// dates (`HH:MM_YYYYMMDD`, `YYYYMMDD`, `MM/DD/YY`) and graphite's relative
+/// expressions: `now`, `today`, `midnight`, `noon`, `yesterday`, `tomorrow`,
+/// optionally followed by an offset (`yesterday+6h`), or a lone offset
+/// relative to now (`-1h`, `-7d`, `-2days`).
+pub fn parse_time(value: &str, now: i64) -> Result<i64, InvalidTime> {
+    let value = value.trim();
+    let invalid = || InvalidTime(value.to_string());
+
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        // 8 digits could be graphite's YYYYMMDD.
+        if value.len() == 8 && value[0..4].parse::<i32>().unwrap_or(0) > 1900 {
+            if let Some(ts) = parse_date(value) {
+                return Ok(ts);
+            }
+        }
+
+        return value.parse::<i64>().map_err(|_| invalid());
+    }
+
+    if let Some(ts) = parse_date(value) {
+        return Ok(ts);
+    }
+
+    let pos = value.find(&['+', '-'][..]).unwrap_or(value.len());
+    let (reference, offset) = value.split_at(pos);
+
+    let midnight = now - now.rem_euclid(86400);
+    let base = match reference.to_lowercase().as_str() {
+        "" | "now" => now,
+        "today" | "midnight" => midnight,
+        "noon" => midnight + 12 * 3600,
+        "yesterday" => midnight - 86400,
+        "tomorrow" => midnight + 86400,
+        _ => return Err(invalid()),
+    };
+
+    if offset.is_empty() {
+        return Ok(base);
+    }
+
+    let seconds = parse_offset(&offset[1..]).ok_or_else(invalid)?;
+
+    let ts = if offset.starts_with('-') {
+        base.checked_sub(seconds)
+    } else {
+        base.checked_add(seconds)
+    };
+
+    ts.ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-09-13T12:26:40Z
+    const NOW : i64 = 1600000000;
+    const MIDNIGHT : i64 = 1599955200;
+
+    #[test]
+    fn parse_timestamps_and_dates() {
+        assert_eq!(parse_time("1613319240", NOW).unwrap(), 1613319240);
+        assert_eq!(parse_time("2020-09-13T12:26:40Z", NOW).unwrap(), NOW);
+        assert_eq!(parse_time("2020-09-13T14:26:40+02:00", NOW).unwrap(), NOW);
+        assert_eq!(parse_time("2020-09-13 12:26:40", NOW).unwrap(), NOW);
+        assert_eq!(parse_time("2020-09-13", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse_time("20200913", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse_time("09/13/20", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse_time("12:00_20200913", NOW).unwrap(), MIDNIGHT + 12 * 3600);
+    }
+
+    #[test]
+    fn parse_relative_times() {
+        assert_eq!(parse_time("now", NOW).unwrap(), NOW);
+        assert_eq!(parse_time(" -1h ", NOW).unwrap(), NOW - 3600);
+        assert_eq!(parse_time("-5min", NOW).unwrap(), NOW - 300);
+        assert_eq!(parse_time("-2days", NOW).unwrap(), NOW - 2 * 86400);
+        assert_eq!(parse_time("now+1w", NOW).unwrap(), NOW + 7 * 86400);
+        assert_eq!(parse_time("midnight", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse_time("noon", NOW).unwrap(), MIDNIGHT + 12 * 3600);
+        assert_eq!(parse_time("yesterday+6h", NOW).unwrap(), MIDNIGHT - 86400 + 6 * 3600);
+        assert_eq!(parse_time("Tomorrow", NOW).unwrap(), MIDNIGHT + 86400);
+    }
+
+    #[test]
+    fn parse_invalid_times() {
+        assert!(parse_time("later", NOW).is_err());
+        assert!(parse_time("-1", NOW).is_err());
+        assert!(parse_time("-1fortnight", NOW).is_err());
+        assert!(parse_time("2020-13-45", NOW).is_err());
+        assert!(parse_time("-9999999999999999y", NOW).is_err());
+        assert!(parse_time("now+9223372036854775000s", NOW).is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30s").unwrap(), 30);
+        assert_eq!(parse_duration("12h").unwrap(), 12 * 3600);
+        assert_eq!(parse_duration("2weeks").unwrap(), 14 * 86400);
+        assert_eq!(parse_duration("1mon").unwrap(), 30 * 86400);
+        assert_eq!(parse_duration("1y").unwrap(), 365 * 86400);
+
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("9999999999999999y").is_err());
+    }
+}
@@ -30,9 +30,10 @@ impl TimeRange {
         let mut offset_start = first_offset.1;
 
         let mut out = vec![];
+        let points_per_row = self.stage.table_row_size_ms() / (self.stage.precision_as_seconds() * 1000);
 
         while offset != last_offset.0 {
-            out.push((offset, offset_start, self.stage.table_row_size_ms()));
+            out.push((offset, offset_start, points_per_row));
 
             offset_start = 0;
             offset += self.stage.table_row_size_ms();
@@ -49,3 +50,37 @@ impl fmt::Display for TimeRange {
         write!(f, "{} ({} -> {})", self.stage, self.time_start, self.time_end)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    #[test]
+    fn ranges_of_a_row() {
+        let stage = Stage::try_from("11520*60s").unwrap();
+        let row = stage.table_row_size_ms() / 1000;
+        let start = 1600000000 - 1600000000 % row;
+
+        assert_eq!(TimeRange::new(&stage, start + 600, start + 1200).ranges(), vec![(start * 1000, 10, 20)]);
+    }
+
+    #[test]
+    fn ranges_across_rows_end_on_points_per_row() {
+        // Rows of 2000 points: bounds are offsets, not milliseconds, and
+        // fit the `offset` smallint column.
+        for stage in &["11520*60s", "720*3600s", "730*86400s"] {
+            let stage = Stage::try_from(*stage).unwrap();
+            let row = stage.table_row_size_ms() / 1000;
+            let step = stage.precision_as_seconds();
+            let start = 1600000000 - 1600000000 % row;
+
+            let ranges = TimeRange::new(&stage, start + 10 * step, start + row + 5 * step).ranges();
+            assert_eq!(ranges, vec![
+                (start * 1000, 10, 2000),
+                ((start + row) * 1000, 0, 5),
+            ]);
+        }
+    }
+}